@@ -0,0 +1,310 @@
+use std::net::{TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+use error::{Result, Error};
+
+
+/// Page shown in the user's browser once we've captured the authorization code.
+const REDIRECT_SUCCESS_PAGE: &'static str = "<html><head><title>Amazon Cloud Drive</title></head><body><p>Authorization complete.  You can close this tab.</p></body></html>";
+
+/// Page shown in the user's browser if the redirect didn't carry an authorization code.
+const REDIRECT_FAILURE_PAGE: &'static str = "<html><head><title>Amazon Cloud Drive</title></head><body><p>Authorization failed.  You can close this tab.</p></body></html>";
+
+/// How many seconds ConsolePrompt waits for the user to finish logging in
+const REDIRECT_TIMEOUT: u64 = 10*60;
+
+/// How many seconds we wait for a connection to the redirect listener to send its request
+const REDIRECT_READ_TIMEOUT: u64 = 10;
+
+/// How many milliseconds between checks for a connection to the redirect listener
+const REDIRECT_POLL_INTERVAL: u64 = 100;
+
+
+/// OAuth scopes that can be requested from Amazon Cloud Drive.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+	/// Read access to all files
+	ReadAll,
+	/// Read access to images only
+	ReadImage,
+	/// Read access to videos only
+	ReadVideo,
+	/// Read access to documents only
+	ReadDocument,
+	/// Read access to files that aren't images, videos or documents
+	ReadOther,
+	/// Upload, create folders, and trash nodes
+	Write,
+}
+
+/// The scopes a Client asks for unless told otherwise.
+pub const DEFAULT_SCOPES: &'static [Scope] = &[Scope::ReadAll, Scope::Write];
+
+/// Scopes for tools that must never be able to modify the user's Cloud Drive.
+pub const READ_ONLY_SCOPES: &'static [Scope] = &[Scope::ReadAll];
+
+impl Scope {
+	pub fn as_str(&self) -> &'static str {
+		match *self {
+			Scope::ReadAll => "clouddrive:read_all",
+			Scope::ReadImage => "clouddrive:read_image",
+			Scope::ReadVideo => "clouddrive:read_video",
+			Scope::ReadDocument => "clouddrive:read_document",
+			Scope::ReadOther => "clouddrive:read_other",
+			Scope::Write => "clouddrive:write",
+		}
+	}
+}
+
+
+/// Walks the user through giving our app access to their Cloud Drive.
+/// `Client` calls this whenever it has no usable authorization.  Implementations must send the
+/// user to `authorization_url` somehow, and return the `code` that Amazon attaches to
+/// `redirect_uri` once the user has logged in and accepted.
+pub trait AuthPrompt {
+	fn authorize(&mut self, authorization_url: &str, redirect_uri: &str) -> Result<String>;
+}
+
+
+/// The interactive, console based prompt.
+/// Opens the authorization URL in the user's web browser and catches the redirect on a local
+/// listener.  If we can't listen on the redirect port, the user is asked to paste the redirect
+/// URL into the console instead.
+pub struct ConsolePrompt;
+
+impl AuthPrompt for ConsolePrompt {
+	fn authorize(&mut self, authorization_url: &str, redirect_uri: &str) -> Result<String> {
+		/* Start listening for the redirect before sending the user off, so we don't miss it */
+		let redirect_listener = bind_redirect_listener(redirect_uri);
+
+		/* First, direct the user to the Amazon login page */
+		if let Err(err) = open_webbrowser(authorization_url) {
+			println!("Unable to open a web browser ({}).", err);
+			println!("Open this URL to authorize access to your Amazon Cloud Drive:");
+			println!("{}", authorization_url);
+		}
+
+		/* After they login, their browser will redirect to the authorization URL which contains the
+		 * code we need.  Normally we catch that redirect ourselves.  If we couldn't listen on the
+		 * redirect port, the user should copy the URL from their browser and paste it into the console
+		 */
+		match redirect_listener {
+			Ok(listener) => {
+				println!("Waiting for authorization to complete in your browser...");
+				wait_for_redirect(&listener, Duration::from_secs(REDIRECT_TIMEOUT))
+			},
+			Err(err) => {
+				println!("Unable to listen for the authorization redirect ({}).", err);
+				println!("Paste the response url:");
+				let mut response_url = String::new();
+				try!(io::stdin().read_line(&mut response_url));
+				code_from_redirect_url(&response_url)
+			},
+		}
+	}
+}
+
+
+/// A prompt for daemons and servers, where there is nobody around to log in.
+/// Never touches the console or a browser; authorization simply fails with
+/// `Error::NotAuthorized`.  Use this when credentials are provisioned ahead of time.
+pub struct NonInteractivePrompt;
+
+impl AuthPrompt for NonInteractivePrompt {
+	fn authorize(&mut self, _authorization_url: &str, _redirect_uri: &str) -> Result<String> {
+		Err(Error::NotAuthorized)
+	}
+}
+
+
+/// Bind a listener on the host and port named by `redirect_uri`, so that we can catch the
+/// browser when Amazon redirects it back to us.
+pub fn bind_redirect_listener(redirect_uri: &str) -> Result<TcpListener> {
+	let url = try!(Url::parse(redirect_uri));
+	let host = try!(url.serialize_host().ok_or(Error::BadAuthUrl));
+	let port = try!(url.port_or_default().ok_or(Error::BadAuthUrl));
+
+	Ok(try!(TcpListener::bind((&host[..], port))))
+}
+
+
+/// Wait for the browser to hit `listener` with the OAuth redirect and return the `code` from it.
+/// Requests that don't look like the redirect (e.g. the browser asking for /favicon.ico) are
+/// answered with a 404, and malformed ones with a 400, and ignored.  Gives up with a `TimedOut`
+/// I/O error if the redirect hasn't arrived within `timeout`.
+pub fn wait_for_redirect(listener: &TcpListener, timeout: Duration) -> Result<String> {
+	let deadline = Instant::now() + timeout;
+
+	// Poll, so that we notice the deadline passing even if nobody ever connects
+	try!(listener.set_nonblocking(true));
+
+	loop {
+		let stream = match listener.accept() {
+			Ok((stream, _)) => stream,
+			Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+				if Instant::now() >= deadline {
+					return Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the authorization redirect")));
+				}
+				thread::sleep(Duration::from_millis(REDIRECT_POLL_INTERVAL));
+				continue;
+			},
+			Err(err) => return Err(Error::from(err)),
+		};
+
+		// A client that misbehaves (or stalls) only loses its own connection
+		match handle_redirect_request(stream) {
+			Ok(Some(result)) => return result,
+			Ok(None) | Err(_) => (),
+		}
+	}
+}
+
+
+/// Answer one request to the redirect listener.  Some(code, or error if the user declined) if it
+/// was the redirect, None if it was something else.
+fn handle_redirect_request(mut stream: TcpStream) -> Result<Option<Result<String>>> {
+	try!(stream.set_nonblocking(false));
+	try!(stream.set_read_timeout(Some(Duration::from_secs(REDIRECT_READ_TIMEOUT))));
+
+	let request_line = {
+		let mut reader = BufReader::new(&stream);
+		let mut request_line = String::new();
+		try!(reader.read_line(&mut request_line));
+
+		// Drain the headers so the browser doesn't see the connection reset under it.
+		loop {
+			let mut header = String::new();
+			if try!(reader.read_line(&mut header)) == 0 || header.trim().is_empty() {
+				break;
+			}
+		}
+
+		request_line
+	};
+
+	// Request line looks like "GET /?code=xyz&scope=abc HTTP/1.1"
+	let url = match request_line.split_whitespace().nth(1) {
+		Some(path) => Url::parse(&("http://localhost".to_owned() + path)).ok(),
+		None => None,
+	};
+	let url = match url {
+		Some(url) => url,
+		None => {
+			try!(write_http_response(&mut stream, "400 Bad Request", ""));
+			return Ok(None);
+		},
+	};
+	let query_pairs = url.query_pairs().unwrap_or_default();
+
+	if let Some(&(_, ref code)) = query_pairs.iter().find(|&x| x.0 == "code") {
+		try!(write_http_response(&mut stream, "200 OK", REDIRECT_SUCCESS_PAGE));
+		return Ok(Some(Ok(code.clone())));
+	}
+
+	if query_pairs.iter().any(|x| x.0 == "error") {
+		try!(write_http_response(&mut stream, "200 OK", REDIRECT_FAILURE_PAGE));
+		return Ok(Some(Err(Error::BadAuthUrl)));
+	}
+
+	try!(write_http_response(&mut stream, "404 Not Found", ""));
+	Ok(None)
+}
+
+
+/// Pull the authorization `code` out of the URL that Amazon redirected the browser to.
+pub fn code_from_redirect_url(redirect_url: &str) -> Result<String> {
+	let response_pairs = try!(try!(Url::parse(redirect_url.trim())).query_pairs().ok_or(Error::BadAuthUrl));
+	let code = try!(response_pairs.iter().find(|&x| x.0 == "code").ok_or(Error::BadAuthUrl));
+	Ok(code.1.clone())
+}
+
+
+fn open_webbrowser(url: &str) -> Result<()> {
+	let status = try!(Command::new("xdg-open").arg(url).status());
+
+	if status.success() {
+		Ok(())
+	} else {
+		Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("xdg-open exited with {}", status))))
+	}
+}
+
+
+fn write_http_response<W: Write>(stream: &mut W, status: &str, body: &str) -> Result<()> {
+	try!(write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body));
+	try!(stream.flush());
+	Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{wait_for_redirect, code_from_redirect_url, AuthPrompt, NonInteractivePrompt};
+	use error::Error;
+	use std::net::{TcpListener, TcpStream};
+	use std::io::{self, Read, Write};
+	use std::thread;
+	use std::time::Duration;
+
+	fn fake_browser_request(port: u16, path: &str) -> String {
+		raw_request(port, &format!("GET {} HTTP/1.1\r\nHost: localhost:{}\r\nUser-Agent: test\r\n\r\n", path, port))
+	}
+
+	fn raw_request(port: u16, request: &str) -> String {
+		let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+		stream.write_all(request.as_bytes()).unwrap();
+		let mut response = String::new();
+		stream.read_to_string(&mut response).unwrap();
+		response
+	}
+
+	#[test]
+	fn test_wait_for_redirect() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		let browser = thread::spawn(move || {
+			let favicon = fake_browser_request(port, "/favicon.ico");
+			let garbage = raw_request(port, "NONSENSE\r\n\r\n");
+			let bad_url = fake_browser_request(port, ":99999/?code=x");
+			let redirect = fake_browser_request(port, "/?code=ANdNAVhyhqirUelHGEHA&scope=clouddrive%3Aread_all+clouddrive%3Awrite");
+			(favicon, garbage, bad_url, redirect)
+		});
+
+		assert_eq!(wait_for_redirect(&listener, Duration::from_secs(30)).unwrap(), "ANdNAVhyhqirUelHGEHA");
+
+		let (favicon, garbage, bad_url, redirect) = browser.join().unwrap();
+		assert!(favicon.starts_with("HTTP/1.1 404"));
+		assert!(garbage.starts_with("HTTP/1.1 400"));
+		assert!(bad_url.starts_with("HTTP/1.1 400"));
+		assert!(redirect.starts_with("HTTP/1.1 200"));
+		assert!(redirect.contains("You can close this tab"));
+	}
+
+	#[test]
+	fn test_wait_for_redirect_timeout() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+		match wait_for_redirect(&listener, Duration::from_millis(200)) {
+			Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::TimedOut => (),
+			_ => panic!("wait_for_redirect should give up once the timeout passes"),
+		}
+	}
+
+	#[test]
+	fn test_non_interactive_prompt() {
+		match NonInteractivePrompt.authorize("https://www.amazon.com/ap/oa?client_id=x", "http://localhost:26619/") {
+			Err(Error::NotAuthorized) => (),
+			_ => panic!("NonInteractivePrompt should refuse to authorize"),
+		}
+	}
+
+	#[test]
+	fn test_code_from_redirect_url() {
+		assert_eq!(code_from_redirect_url("http://localhost:26619/?code=abc123&scope=clouddrive%3Aread_all\n").unwrap(), "abc123");
+		assert!(code_from_redirect_url("http://localhost:26619/?scope=clouddrive%3Aread_all").is_err());
+	}
+}
@@ -0,0 +1,293 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::time::Duration;
+use rest::{RequestSettings, check_url};
+use error::Result;
+use auth::{AuthPrompt, ConsolePrompt, Scope, DEFAULT_SCOPES};
+use store::{TokenStore, JsonFileStore, MemoryStore};
+use transport::{Transport, HyperTransport};
+use super::{Client, SecurityProfile, Authorization, Endpoint, NodeId};
+
+
+/// Amazon's login page, where the user authorizes our app.
+pub const DEFAULT_AUTHORIZE_URL: &'static str = "https://www.amazon.com/ap/oa";
+
+/// Amazon's OAuth token endpoint.
+pub const DEFAULT_TOKEN_URL: &'static str = "https://api.amazon.com/auth/o2/token";
+
+/// Where the account's metadata and content URLs are looked up.
+pub const DEFAULT_ENDPOINT_URL: &'static str = "https://drive.amazonaws.com/drive/v1/account/endpoint";
+
+/// How long we wait on a request's socket before giving up (and retrying)
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// How many times we retry contacting Amazon after a server error, by default
+const DEFAULT_MAXIMUM_RETRY: u32 = 8;
+
+/// Upper bound of the first retry's random backoff, by default (in milliseconds)
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 1000;
+
+
+/// Configures and creates a `Client`.
+///
+/// ```no_run
+/// use acd::{ClientBuilder, NonInteractivePrompt, READ_ONLY_SCOPES};
+///
+/// let client = ClientBuilder::new("amzn1.application-oa2-client.xyz", "secret")
+///     .config_dir("/var/lib/backup")
+///     .scopes(READ_ONLY_SCOPES)
+///     .auth_prompt(Box::new(NonInteractivePrompt))
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// With a `config_dir`, the Authorization and Endpoint are kept in JSON files and the node cache
+/// in cache.sqlite, all under config_dir/.acd (unless overridden by `token_store` and `cache_path`).
+/// Without one, everything defaults to living in memory for the lifetime of the Client.
+pub struct ClientBuilder {
+	client_id: String,
+	client_secret: String,
+	config_dir: Option<PathBuf>,
+	cache_path: Option<PathBuf>,
+	token_store: Option<Box<TokenStore>>,
+	auth_prompt: Option<Box<AuthPrompt>>,
+	scopes: Vec<Scope>,
+	maximum_retry: u32,
+	retry_backoff: Duration,
+	request_settings: RequestSettings,
+	transport: Option<Box<Transport>>,
+	authorize_url: String,
+	token_url: String,
+	endpoint_url: String,
+	endpoint: Option<(String, String)>,
+	revocation_url: Option<String>,
+	lazy: bool,
+}
+
+impl ClientBuilder {
+	/// client_id and client_secret come from an Amazon security profile.  See `Client::new`.
+	pub fn new(client_id: &str, client_secret: &str) -> ClientBuilder {
+		ClientBuilder {
+			client_id: client_id.to_owned(),
+			client_secret: client_secret.to_owned(),
+			config_dir: None,
+			cache_path: None,
+			token_store: None,
+			auth_prompt: None,
+			scopes: DEFAULT_SCOPES.to_vec(),
+			maximum_retry: DEFAULT_MAXIMUM_RETRY,
+			retry_backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF_MS),
+			request_settings: RequestSettings {
+				read_timeout: Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)),
+				write_timeout: Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)),
+				user_agent: None,
+			},
+			transport: None,
+			authorize_url: DEFAULT_AUTHORIZE_URL.to_owned(),
+			token_url: DEFAULT_TOKEN_URL.to_owned(),
+			endpoint_url: DEFAULT_ENDPOINT_URL.to_owned(),
+			endpoint: None,
+			revocation_url: None,
+			lazy: false,
+		}
+	}
+
+	/// Keep configuration (authorization, endpoint, node cache) under config_dir/.acd
+	pub fn config_dir<P: AsRef<Path>>(mut self, config_dir: P) -> ClientBuilder {
+		self.config_dir = Some(config_dir.as_ref().join(".acd"));
+		self
+	}
+
+	/// Where to keep the node cache (an SQLite database).
+	pub fn cache_path<P: AsRef<Path>>(mut self, cache_path: P) -> ClientBuilder {
+		self.cache_path = Some(cache_path.as_ref().to_path_buf());
+		self
+	}
+
+	/// Where to load and save the Authorization and Endpoint.
+	pub fn token_store(mut self, token_store: Box<TokenStore>) -> ClientBuilder {
+		self.token_store = Some(token_store);
+		self
+	}
+
+	/// How the user is asked for authorization.  Defaults to `ConsolePrompt`.
+	pub fn auth_prompt(mut self, auth_prompt: Box<AuthPrompt>) -> ClientBuilder {
+		self.auth_prompt = Some(auth_prompt);
+		self
+	}
+
+	/// The OAuth scopes to ask for.  Defaults to DEFAULT_SCOPES.  Pass READ_ONLY_SCOPES for a
+	/// client that can never modify the Cloud Drive; its write methods fail with
+	/// `Error::MissingWriteScope`.  A saved authorization that wasn't granted exactly `scopes`
+	/// (say, a read+write one, for a read-only client) is discarded and the user asked again.
+	pub fn scopes(mut self, scopes: &[Scope]) -> ClientBuilder {
+		self.scopes = scopes.to_vec();
+		self
+	}
+
+	/// How many times we retry contacting Amazon after a server or communication error.
+	pub fn maximum_retry(mut self, maximum_retry: u32) -> ClientBuilder {
+		self.maximum_retry = maximum_retry;
+		self
+	}
+
+	/// Before each retry we wait a random time, up to `backoff` for the first retry and doubling
+	/// for each one after that.  Zero retries immediately, which is mostly useful for tests.
+	pub fn retry_backoff(mut self, backoff: Duration) -> ClientBuilder {
+		self.retry_backoff = backoff;
+		self
+	}
+
+	/// Read timeout for each request.  None waits forever.  Only used by the default transport.
+	pub fn read_timeout(mut self, timeout: Option<Duration>) -> ClientBuilder {
+		self.request_settings.read_timeout = timeout;
+		self
+	}
+
+	/// Write timeout for each request.  None waits forever.  Only used by the default transport.
+	pub fn write_timeout(mut self, timeout: Option<Duration>) -> ClientBuilder {
+		self.request_settings.write_timeout = timeout;
+		self
+	}
+
+	/// User-Agent header sent with every request.
+	pub fn user_agent(mut self, user_agent: &str) -> ClientBuilder {
+		self.request_settings.user_agent = Some(user_agent.to_owned());
+		self
+	}
+
+	/// How requests are sent.  Defaults to a `HyperTransport`, over the network.  Swap in a
+	/// `RecordingTransport` or `ReplayTransport` to capture or replay a session.
+	pub fn transport(mut self, transport: Box<Transport>) -> ClientBuilder {
+		self.transport = Some(transport);
+		self
+	}
+
+	// The service URLs below can point anywhere that speaks the Cloud Drive API, including a plain
+	// http server on localhost, which is handy for testing.  They're checked by `build`.
+
+	/// The login page the user is sent to.  Defaults to DEFAULT_AUTHORIZE_URL.
+	pub fn authorize_url(mut self, url: &str) -> ClientBuilder {
+		self.authorize_url = url.to_owned();
+		self
+	}
+
+	/// The OAuth token endpoint.  Defaults to DEFAULT_TOKEN_URL.
+	pub fn token_url(mut self, url: &str) -> ClientBuilder {
+		self.token_url = url.to_owned();
+		self
+	}
+
+	/// Where the account's metadata and content URLs are looked up.  Defaults to DEFAULT_ENDPOINT_URL.
+	pub fn endpoint_url(mut self, url: &str) -> ClientBuilder {
+		self.endpoint_url = url.to_owned();
+		self
+	}
+
+	/// Use these metadata and content base URLs instead of asking Amazon for the account's
+	/// endpoint.
+	pub fn endpoint(mut self, metadata_url: &str, content_url: &str) -> ClientBuilder {
+		self.endpoint = Some((metadata_url.to_owned(), content_url.to_owned()));
+		self
+	}
+
+	/// OAuth token revocation endpoint (RFC 7009) that `Client::logout` should notify.
+	/// Without one, `logout` only forgets the tokens locally.
+	pub fn revocation_url(mut self, url: &str) -> ClientBuilder {
+		self.revocation_url = Some(url.to_owned());
+		self
+	}
+
+	/// If true, `build` doesn't contact Amazon (or prompt the user); authorization, the endpoint
+	/// lookup and finding the root folder happen on first use instead.  See `Client::connect`.
+	pub fn lazy(mut self, lazy: bool) -> ClientBuilder {
+		self.lazy = lazy;
+		self
+	}
+
+	pub fn build(self) -> Result<Client> {
+		try!(check_url(&self.authorize_url));
+		try!(check_url(&self.token_url));
+		try!(check_url(&self.endpoint_url));
+		if let Some((ref metadata_url, ref content_url)) = self.endpoint {
+			try!(check_url(metadata_url));
+			try!(check_url(content_url));
+		}
+		if let Some(ref revocation_url) = self.revocation_url {
+			try!(check_url(revocation_url));
+		}
+
+		if let Some(ref config_dir) = self.config_dir {
+			try!(fs::create_dir_all(config_dir));
+		}
+
+		let cache_path = self.cache_path.or(self.config_dir.as_ref().map(|dir| dir.join("cache.sqlite")));
+		let cache_conn = try!(Client::init_cache(cache_path.as_ref().map(|path| path.as_path())));
+
+		let mut token_store = match (self.token_store, self.config_dir) {
+			(Some(token_store), _) => token_store,
+			(None, Some(config_dir)) => Box::new(JsonFileStore::new(config_dir)) as Box<TokenStore>,
+			(None, None) => Box::new(MemoryStore::new()) as Box<TokenStore>,
+		};
+
+		let read_timeout = self.request_settings.read_timeout;
+		let write_timeout = self.request_settings.write_timeout;
+		let transport = self.transport.unwrap_or_else(|| Box::new(HyperTransport::new(read_timeout, write_timeout)) as Box<Transport>);
+
+		// Read existing endpoint or start from scratch.
+		let fixed_endpoint = self.endpoint.is_some();
+		let endpoint = match self.endpoint {
+			Some((metadata_url, content_url)) => Endpoint {
+				content_url: content_url,
+				metadata_url: metadata_url,
+				date_last_updated: 0,
+			},
+			None => try!(token_store.load_endpoint()).unwrap_or(Endpoint {
+				content_url: String::new(),
+				metadata_url: String::new(),
+				date_last_updated: 0,
+			}),
+		};
+
+		// Read existing authorization or start from scratch.
+		let authorization = try!(token_store.load_authorization()).unwrap_or(Authorization {
+			access_token: String::new(),
+			refresh_token: String::new(),
+			token_type: String::new(),
+			date_last_updated: 0,
+			expires_at: None,
+			scopes: None,
+		});
+
+		let mut acd = Client {
+			security_profile: SecurityProfile {
+				client_id: self.client_id,
+				client_secret: self.client_secret,
+			},
+			authorization: authorization,
+			endpoint: endpoint,
+			fixed_endpoint: fixed_endpoint,
+			root_id: NodeId(String::new()),
+			connected: false,
+			cache_connection: cache_conn,
+			cache_path: cache_path,
+			transport: transport,
+			request_settings: self.request_settings,
+			auth_prompt: self.auth_prompt.unwrap_or(Box::new(ConsolePrompt)),
+			token_store: token_store,
+			maximum_retry: self.maximum_retry,
+			retry_backoff: self.retry_backoff,
+			authorize_url: self.authorize_url,
+			token_url: self.token_url,
+			endpoint_url: self.endpoint_url,
+			revocation_url: self.revocation_url,
+			scopes: self.scopes,
+		};
+
+		if !self.lazy {
+			try!(acd.connect());
+		}
+
+		Ok(acd)
+	}
+}
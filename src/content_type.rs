@@ -0,0 +1,61 @@
+use std::path::Path;
+use mime::Mime;
+
+
+/// What uploads are labelled as when we don't know any better.
+pub const DEFAULT_CONTENT_TYPE: &'static str = "application/octet-stream";
+
+/// File extensions (lowercase) and the MIME types Cloud Drive's apps understand them as.
+const CONTENT_TYPES: &'static [(&'static str, &'static str)] = &[
+	("txt", "text/plain"),
+	("htm", "text/html"),
+	("html", "text/html"),
+	("css", "text/css"),
+	("csv", "text/csv"),
+	("md", "text/markdown"),
+	("js", "application/javascript"),
+	("json", "application/json"),
+	("xml", "application/xml"),
+	("pdf", "application/pdf"),
+	("zip", "application/zip"),
+	("gz", "application/gzip"),
+	("tar", "application/x-tar"),
+	("bz2", "application/x-bzip2"),
+	("xz", "application/x-xz"),
+	("7z", "application/x-7z-compressed"),
+	("doc", "application/msword"),
+	("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+	("xls", "application/vnd.ms-excel"),
+	("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+	("ppt", "application/vnd.ms-powerpoint"),
+	("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+	("jpg", "image/jpeg"),
+	("jpeg", "image/jpeg"),
+	("png", "image/png"),
+	("gif", "image/gif"),
+	("bmp", "image/bmp"),
+	("tif", "image/tiff"),
+	("tiff", "image/tiff"),
+	("svg", "image/svg+xml"),
+	("webp", "image/webp"),
+	("mp3", "audio/mpeg"),
+	("m4a", "audio/mp4"),
+	("flac", "audio/flac"),
+	("ogg", "audio/ogg"),
+	("wav", "audio/wav"),
+	("mp4", "video/mp4"),
+	("m4v", "video/mp4"),
+	("mov", "video/quicktime"),
+	("avi", "video/x-msvideo"),
+	("mkv", "video/x-matroska"),
+	("webm", "video/webm"),
+];
+
+
+/// Guess the content type of the file at `path` from its extension.
+pub fn from_path(path: &Path) -> Mime {
+	let extension = path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_lowercase());
+	let content_type = extension.and_then(|extension| CONTENT_TYPES.iter().find(|x| x.0 == extension).map(|x| x.1));
+
+	content_type.unwrap_or(DEFAULT_CONTENT_TYPE).parse().unwrap()
+}
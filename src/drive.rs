@@ -0,0 +1,701 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf, Component};
+use mime::Mime;
+use error::{Result, Error};
+use crypto::md5::Md5;
+use crypto::digest::Digest;
+use time;
+use node::{Node, NodeKind, NodeStatus};
+use list::{self, ListOptions};
+use super::{Client, NodeId, md5_hex, rfc3339};
+
+
+/// The file operations of a Cloud Drive.
+/// Implemented by `Client`, which talks to Amazon, and by `MemoryDrive` and `LocalDrive`, which
+/// don't.  Code written against `CloudDrive` (rather than `Client`) can be tested without the
+/// network.
+///
+/// Implementations follow Amazon's semantics: names are unique within a folder, uploading over an
+/// existing name fails with `Error::NodeExists`, `mkdir` of an existing name returns the existing
+/// node, and `rm` moves nodes to the trash rather than deleting them.
+pub trait CloudDrive {
+	/// The root folder.
+	fn root(&mut self) -> Result<NodeId>;
+
+	/// Find the node called `name` in folder `parent`.
+	fn find_child(&mut self, parent: &NodeId, name: &str) -> Result<Option<NodeId>>;
+
+	/// Find a node using an absolute or relative path.
+	/// Returns None if the path could not be found.
+	fn find_path(&mut self, parent: Option<&NodeId>, path: &Path) -> Result<Option<NodeId>> {
+		find_path(self, parent, path)
+	}
+
+	/// Metadata for node `id`.
+	fn get_node(&mut self, id: &NodeId) -> Result<Node>;
+
+	/// Metadata for the node at an absolute or relative path.
+	/// Returns None if the path could not be found.
+	fn stat(&mut self, parent: Option<&NodeId>, path: &Path) -> Result<Option<Node>> {
+		stat(self, parent, path)
+	}
+
+	/// Upload `data` as a new file called `name` in folder `parent` (or the root if None).
+	fn upload(&mut self, parent: Option<&NodeId>, name: &str, data: &[u8], content_type: Option<Mime>) -> Result<NodeId>;
+
+	/// Create folder `name` in `parent` (or the root if None), if it doesn't exist.
+	/// Returns id for created/existing folder.
+	fn mkdir(&mut self, parent: Option<&NodeId>, name: &str) -> Result<NodeId>;
+
+	/// Create all folders in path if they don't exist.
+	/// Returns id for the last folder in the path.
+	fn mkdir_all(&mut self, parent: Option<&NodeId>, path: &Path) -> Result<NodeId> {
+		mkdir_all(self, parent, path)
+	}
+
+	/// The nodes in folder `parent`.
+	fn ls(&mut self, parent: &NodeId) -> Result<Vec<Node>> {
+		self.ls_with(parent, &ListOptions::new())
+	}
+
+	/// The nodes in folder `parent` that match `options`, sorted as `options` asks.
+	fn ls_with(&mut self, parent: &NodeId, options: &ListOptions) -> Result<Vec<Node>>;
+
+	/// Contents of file `id`.
+	fn download(&mut self, id: &NodeId) -> Result<Vec<u8>>;
+
+	/// Move a node to the trash.
+	fn rm(&mut self, node: &NodeId) -> Result<()>;
+}
+
+
+/// `CloudDrive::find_path` in terms of `root` and `find_child`.
+pub fn find_path<D: CloudDrive + ?Sized>(drive: &mut D, parent: Option<&NodeId>, path: &Path) -> Result<Option<NodeId>> {
+	let root = try!(drive.root());
+	let mut current_dir = parent.unwrap_or(&root).clone();
+
+	for p in path.components() {
+		match p {
+			Component::RootDir => current_dir = root.clone(),
+			Component::CurDir => (),
+			Component::Normal(osstr) => match osstr.to_str() {
+				Some(name) => current_dir = match try!(drive.find_child(&current_dir, name)) {
+					Some(child) => child,
+					None => return Ok(None),
+				},
+				None => return Err(Error::BadPath),
+			},
+			_ => return Err(Error::BadPath),
+		}
+	}
+
+	Ok(Some(current_dir))
+}
+
+
+/// `CloudDrive::stat` in terms of `find_path` and `get_node`.
+pub fn stat<D: CloudDrive + ?Sized>(drive: &mut D, parent: Option<&NodeId>, path: &Path) -> Result<Option<Node>> {
+	match try!(drive.find_path(parent, path)) {
+		Some(id) => drive.get_node(&id).map(Some),
+		None => Ok(None),
+	}
+}
+
+
+/// `CloudDrive::mkdir_all` in terms of `root` and `mkdir`.
+pub fn mkdir_all<D: CloudDrive + ?Sized>(drive: &mut D, parent: Option<&NodeId>, path: &Path) -> Result<NodeId> {
+	let root = try!(drive.root());
+	let mut current_dir = parent.unwrap_or(&root).clone();
+
+	for p in path.components() {
+		match p {
+			Component::RootDir => current_dir = root.clone(),
+			Component::CurDir => (),
+			Component::Normal(osstr) => {
+				let name = try!(osstr.to_str().ok_or(Error::BadPath));
+				current_dir = try!(drive.mkdir(Some(&current_dir), name));
+			},
+			_ => return Err(Error::BadPath),
+		}
+	}
+
+	Ok(current_dir)
+}
+
+
+impl CloudDrive for Client {
+	fn root(&mut self) -> Result<NodeId> {
+		try!(self.connect());
+		Ok(self.root_id.clone())
+	}
+
+	fn find_child(&mut self, parent: &NodeId, name: &str) -> Result<Option<NodeId>> {
+		Client::find_child(self, parent, name)
+	}
+
+	fn get_node(&mut self, id: &NodeId) -> Result<Node> {
+		Client::get_node(self, id)
+	}
+
+	fn upload(&mut self, parent: Option<&NodeId>, name: &str, data: &[u8], content_type: Option<Mime>) -> Result<NodeId> {
+		Client::upload(self, parent, name, data, content_type)
+	}
+
+	fn mkdir(&mut self, parent: Option<&NodeId>, name: &str) -> Result<NodeId> {
+		Client::mkdir(self, parent, name)
+	}
+
+	fn ls_with(&mut self, parent: &NodeId, options: &ListOptions) -> Result<Vec<Node>> {
+		Client::ls_with(self, parent, options)
+	}
+
+	fn download(&mut self, id: &NodeId) -> Result<Vec<u8>> {
+		Client::download(self, id)
+	}
+
+	fn rm(&mut self, node: &NodeId) -> Result<()> {
+		Client::rm(self, node)
+	}
+}
+
+
+/// Names Amazon would refuse.
+fn check_name(name: &str) -> Result<()> {
+	if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+		Err(Error::BadPath)
+	} else {
+		Ok(())
+	}
+}
+
+
+/// MD5 of what's left in `reader`, as lowercase hex.  Reads a chunk at a time, so it doesn't
+/// matter how much that is.
+fn md5_hex_reader<R: Read>(mut reader: R) -> io::Result<String> {
+	let mut md5 = Md5::new();
+	let mut buffer = vec![0u8; 64*1024];
+
+	loop {
+		match reader.read(&mut buffer) {
+			Ok(0) => break,
+			Ok(n) => md5.input(&buffer[..n]),
+			Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+			Err(err) => return Err(err),
+		}
+	}
+
+	Ok(md5.result_str().to_lowercase())
+}
+
+
+struct MemoryNode {
+	name: String,
+	parent: Option<NodeId>,
+	/// None for folders
+	content: Option<Vec<u8>>,
+	content_type: Option<String>,
+	trashed: bool,
+	created_date: String,
+	modified_date: String,
+	version: u64,
+}
+
+impl MemoryNode {
+	fn new(name: &str, parent: Option<NodeId>, content: Option<Vec<u8>>, content_type: Option<String>) -> MemoryNode {
+		let now = format!("{}", time::now_utc().rfc3339());
+
+		MemoryNode {
+			name: name.to_owned(),
+			parent: parent,
+			content: content,
+			content_type: content_type,
+			trashed: false,
+			created_date: now.clone(),
+			modified_date: now,
+			version: 1,
+		}
+	}
+}
+
+
+/// A `CloudDrive` that lives in memory, for tests.  Starts out empty except for the root folder.
+pub struct MemoryDrive {
+	/// Keyed by id; ids are assigned in increasing order, so this is also creation order
+	nodes: BTreeMap<String, MemoryNode>,
+	root_id: NodeId,
+	next_id: u64,
+}
+
+impl MemoryDrive {
+	pub fn new() -> MemoryDrive {
+		let mut drive = MemoryDrive {
+			nodes: BTreeMap::new(),
+			root_id: NodeId(String::new()),
+			next_id: 0,
+		};
+
+		drive.root_id = drive.insert(MemoryNode::new("", None, None, None));
+		drive
+	}
+
+	/// Names and ids of everything that has been `rm`'d.
+	pub fn trash(&self) -> Vec<(String, NodeId)> {
+		self.nodes.iter()
+			.filter(|&(_, node)| node.trashed)
+			.map(|(id, node)| (node.name.clone(), NodeId(id.clone())))
+			.collect()
+	}
+
+	fn insert(&mut self, node: MemoryNode) -> NodeId {
+		self.next_id += 1;
+		let id = format!("memoryNode{:012}", self.next_id);
+		self.nodes.insert(id.clone(), node);
+		NodeId(id)
+	}
+
+	/// Check that `parent` is a folder we can create `name` in, and return the existing node called
+	/// `name`, if any.
+	fn check_create(&self, parent: &NodeId, name: &str) -> Result<Option<NodeId>> {
+		try!(check_name(name));
+
+		match self.nodes.get(&parent.0) {
+			Some(node) if node.content.is_none() => (),
+			_ => return Err(Error::NodeNotFound),
+		}
+
+		self.find_available_child(parent, name)
+	}
+
+	fn find_available_child(&self, parent: &NodeId, name: &str) -> Result<Option<NodeId>> {
+		Ok(self.nodes.iter()
+			.find(|&(_, node)| !node.trashed && node.name == name && node.parent.as_ref() == Some(parent))
+			.map(|(id, _)| NodeId(id.clone())))
+	}
+}
+
+impl Default for MemoryDrive {
+	fn default() -> MemoryDrive {
+		MemoryDrive::new()
+	}
+}
+
+impl CloudDrive for MemoryDrive {
+	fn root(&mut self) -> Result<NodeId> {
+		Ok(self.root_id.clone())
+	}
+
+	fn find_child(&mut self, parent: &NodeId, name: &str) -> Result<Option<NodeId>> {
+		if !self.nodes.contains_key(&parent.0) {
+			return Err(Error::NodeNotFound);
+		}
+
+		self.find_available_child(parent, name)
+	}
+
+	fn get_node(&mut self, id: &NodeId) -> Result<Node> {
+		let node = try!(self.nodes.get(&id.0).ok_or(Error::NodeNotFound));
+
+		Ok(Node {
+			id: id.clone(),
+			name: if *id == self.root_id { None } else { Some(node.name.clone()) },
+			kind: if node.content.is_some() { NodeKind::File } else { NodeKind::Folder },
+			parents: node.parent.iter().cloned().collect(),
+			status: if node.trashed { NodeStatus::Trash } else { NodeStatus::Available },
+			size: node.content.as_ref().map(|content| content.len() as u64),
+			md5: node.content.as_ref().map(|content| md5_hex(content)),
+			content_type: node.content.as_ref().map(|_| node.content_type.clone().unwrap_or("application/octet-stream".to_owned())),
+			created_date: node.created_date.clone(),
+			modified_date: node.modified_date.clone(),
+			version: node.version,
+			labels: Vec::new(),
+			description: None,
+			properties: BTreeMap::new(),
+		})
+	}
+
+	fn upload(&mut self, parent: Option<&NodeId>, name: &str, data: &[u8], content_type: Option<Mime>) -> Result<NodeId> {
+		let parent = parent.unwrap_or(&self.root_id).clone();
+
+		if try!(self.check_create(&parent, name)).is_some() {
+			return Err(Error::NodeExists);
+		}
+
+		Ok(self.insert(MemoryNode::new(name, Some(parent), Some(data.to_vec()), content_type.map(|mime| mime.to_string()))))
+	}
+
+	fn mkdir(&mut self, parent: Option<&NodeId>, name: &str) -> Result<NodeId> {
+		let parent = parent.unwrap_or(&self.root_id).clone();
+
+		// Like Amazon, the conflicting node is returned, whatever its kind.
+		if let Some(existing) = try!(self.check_create(&parent, name)) {
+			return Ok(existing);
+		}
+
+		Ok(self.insert(MemoryNode::new(name, Some(parent), None, None)))
+	}
+
+	fn ls_with(&mut self, parent: &NodeId, options: &ListOptions) -> Result<Vec<Node>> {
+		if !self.nodes.contains_key(&parent.0) {
+			return Err(Error::NodeNotFound);
+		}
+
+		let ids: Vec<NodeId> = self.nodes.iter()
+			.filter(|&(_, node)| node.parent.as_ref() == Some(parent))
+			.map(|(id, _)| NodeId(id.clone()))
+			.collect();
+		let mut children = Vec::new();
+
+		for id in ids {
+			children.push(try!(self.get_node(&id)));
+		}
+
+		Ok(list::apply_locally(options, children))
+	}
+
+	fn download(&mut self, id: &NodeId) -> Result<Vec<u8>> {
+		match self.nodes.get(&id.0).and_then(|node| node.content.as_ref()) {
+			Some(content) => Ok(content.clone()),
+			None => Err(Error::NodeNotFound),
+		}
+	}
+
+	fn rm(&mut self, node: &NodeId) -> Result<()> {
+		if *node == self.root_id {
+			return Err(Error::NodeNotFound);
+		}
+
+		match self.nodes.get_mut(&node.0) {
+			Some(node) => {
+				if !node.trashed {
+					node.trashed = true;
+					node.modified_date = format!("{}", time::now_utc().rfc3339());
+					node.version += 1;
+				}
+				Ok(())
+			},
+			None => Err(Error::NodeNotFound),
+		}
+	}
+}
+
+
+/// A `CloudDrive` kept in a local directory, for tests and for trying things out offline.
+/// The drive's files live under dir/drive and `rm` moves them to dir/trash.  A node's id is its
+/// path within the drive ("/" is the root), so ids of trashed nodes are no longer valid.
+pub struct LocalDrive {
+	drive_dir: PathBuf,
+	trash_dir: PathBuf,
+}
+
+impl LocalDrive {
+	/// Use (and create, if needed) a drive in `dir`.
+	pub fn new<P: AsRef<Path>>(dir: P) -> Result<LocalDrive> {
+		let drive_dir = dir.as_ref().join("drive");
+		let trash_dir = dir.as_ref().join("trash");
+
+		try!(fs::create_dir_all(&drive_dir));
+		try!(fs::create_dir_all(&trash_dir));
+
+		Ok(LocalDrive {
+			drive_dir: drive_dir,
+			trash_dir: trash_dir,
+		})
+	}
+
+	/// Where `rm` moves things to.
+	pub fn trash_dir(&self) -> &Path {
+		&self.trash_dir
+	}
+
+	/// Local path of node `id`.  Refuses ids that would escape the drive.
+	fn local_path(&self, id: &NodeId) -> Result<PathBuf> {
+		if !id.0.starts_with('/') {
+			return Err(Error::NodeNotFound);
+		}
+
+		let mut path = self.drive_dir.clone();
+
+		for name in id.0.split('/').filter(|name| !name.is_empty()) {
+			try!(check_name(name).map_err(|_| Error::NodeNotFound));
+			path.push(name);
+		}
+
+		Ok(path)
+	}
+
+	fn child_id(parent: &NodeId, name: &str) -> NodeId {
+		if parent.0.ends_with('/') {
+			NodeId(parent.0.clone() + name)
+		} else {
+			NodeId(parent.0.clone() + "/" + name)
+		}
+	}
+
+	/// Check that `parent` is a folder we can create `name` in, and return the local path of `name`.
+	fn check_create(&self, parent: &NodeId, name: &str) -> Result<PathBuf> {
+		try!(check_name(name));
+		let parent_path = try!(self.local_path(parent));
+
+		if !fs::metadata(&parent_path).map(|metadata| metadata.is_dir()).unwrap_or(false) {
+			return Err(Error::NodeNotFound);
+		}
+
+		Ok(parent_path.join(name))
+	}
+}
+
+impl CloudDrive for LocalDrive {
+	fn root(&mut self) -> Result<NodeId> {
+		Ok(NodeId("/".to_owned()))
+	}
+
+	fn find_child(&mut self, parent: &NodeId, name: &str) -> Result<Option<NodeId>> {
+		if check_name(name).is_err() {
+			return Ok(None);
+		}
+
+		let path = try!(self.check_create(parent, name));
+
+		match fs::symlink_metadata(&path) {
+			Ok(_) => Ok(Some(LocalDrive::child_id(parent, name))),
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(err) => Err(Error::from(err)),
+		}
+	}
+
+	fn get_node(&mut self, id: &NodeId) -> Result<Node> {
+		let path = try!(self.local_path(id));
+		let metadata = match fs::metadata(&path) {
+			Ok(metadata) => metadata,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Err(Error::NodeNotFound),
+			Err(err) => return Err(Error::from(err)),
+		};
+
+		// The filesystem doesn't reliably know when a file was created, so both dates are the mtime.
+		let modified_date = metadata.modified().ok().and_then(rfc3339).unwrap_or_default();
+
+		let name = id.0.rsplit('/').next().unwrap_or("");
+		let parent = id.0.trim_right_matches('/').rfind('/').map(|i| if i == 0 { "/".to_owned() } else { id.0[..i].to_owned() });
+
+		let (size, md5, content_type) = if metadata.is_file() {
+			// Hashed a chunk at a time, since ls_with does this for every file in a folder
+			let md5 = try!(md5_hex_reader(try!(File::open(&path))));
+			(Some(metadata.len()), Some(md5), Some("application/octet-stream".to_owned()))
+		} else {
+			(None, None, None)
+		};
+
+		Ok(Node {
+			id: id.clone(),
+			name: if name.is_empty() { None } else { Some(name.to_owned()) },
+			kind: if metadata.is_dir() { NodeKind::Folder } else { NodeKind::File },
+			parents: if name.is_empty() { Vec::new() } else { parent.into_iter().map(NodeId).collect() },
+			status: NodeStatus::Available,
+			size: size,
+			md5: md5,
+			content_type: content_type,
+			created_date: modified_date.clone(),
+			modified_date: modified_date,
+			version: 1,
+			labels: Vec::new(),
+			description: None,
+			properties: BTreeMap::new(),
+		})
+	}
+
+	fn upload(&mut self, parent: Option<&NodeId>, name: &str, data: &[u8], _content_type: Option<Mime>) -> Result<NodeId> {
+		let parent = match parent {
+			Some(parent) => parent.clone(),
+			None => try!(self.root()),
+		};
+		let path = try!(self.check_create(&parent, name));
+
+		let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+			Ok(file) => file,
+			Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => return Err(Error::NodeExists),
+			Err(err) => return Err(Error::from(err)),
+		};
+		try!(file.write_all(data));
+
+		Ok(LocalDrive::child_id(&parent, name))
+	}
+
+	fn mkdir(&mut self, parent: Option<&NodeId>, name: &str) -> Result<NodeId> {
+		let parent = match parent {
+			Some(parent) => parent.clone(),
+			None => try!(self.root()),
+		};
+		let path = try!(self.check_create(&parent, name));
+
+		// Like Amazon, an existing node is returned, whatever its kind.
+		match fs::create_dir(&path) {
+			Ok(_) => (),
+			Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+			Err(err) => return Err(Error::from(err)),
+		}
+
+		Ok(LocalDrive::child_id(&parent, name))
+	}
+
+	fn ls_with(&mut self, parent: &NodeId, options: &ListOptions) -> Result<Vec<Node>> {
+		let path = try!(self.local_path(parent));
+		let entries = match fs::read_dir(&path) {
+			Ok(entries) => entries,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Err(Error::NodeNotFound),
+			Err(err) => return Err(Error::from(err)),
+		};
+
+		let mut children = Vec::new();
+
+		for entry in entries {
+			let entry = try!(entry);
+			// Amazon names are always UTF-8, so anything else can't be part of the drive
+			if let Ok(name) = entry.file_name().into_string() {
+				let id = LocalDrive::child_id(parent, &name);
+				children.push(try!(self.get_node(&id)));
+			}
+		}
+
+		// Trashed nodes aren't children of anything here, so a status of TRASH lists nothing.
+		children.sort_by(|a, b| a.name.cmp(&b.name));
+		Ok(list::apply_locally(options, children))
+	}
+
+	fn download(&mut self, id: &NodeId) -> Result<Vec<u8>> {
+		let path = try!(self.local_path(id));
+
+		if !fs::metadata(&path).map(|metadata| metadata.is_file()).unwrap_or(false) {
+			return Err(Error::NodeNotFound);
+		}
+
+		let mut data = Vec::new();
+		try!(try!(File::open(&path)).read_to_end(&mut data));
+		Ok(data)
+	}
+
+	fn rm(&mut self, node: &NodeId) -> Result<()> {
+		let path = try!(self.local_path(node));
+		let name = try!(path.file_name().ok_or(Error::NodeNotFound)).to_os_string();
+
+		if path == self.drive_dir || fs::symlink_metadata(&path).is_err() {
+			return Err(Error::NodeNotFound);
+		}
+
+		// The trash is flat, so make up a new name if something of the same name is already in it.
+		let mut trash_path = self.trash_dir.join(&name);
+		let mut n = 0;
+
+		while fs::symlink_metadata(&trash_path).is_ok() {
+			n += 1;
+			let mut trash_name = name.clone();
+			trash_name.push(format!(".{}", n));
+			trash_path = self.trash_dir.join(trash_name);
+		}
+
+		try!(fs::rename(&path, &trash_path));
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{CloudDrive, MemoryDrive, LocalDrive};
+	use super::super::NodeId;
+	use node::{NodeKind, NodeStatus};
+	use list::{ListOptions, SortField, SortOrder};
+	use error::Error;
+	use mock::MockServer;
+	use std::path::Path;
+	use tempdir::TempDir;
+
+	/// The same scenario, whatever the implementation
+	fn exercise<D: CloudDrive>(drive: &mut D) {
+		let folder = drive.mkdir_all(None, Path::new("/a/b")).unwrap();
+		let a = drive.find_path(None, Path::new("/a")).unwrap().unwrap();
+		assert_eq!(drive.mkdir(Some(&a), "b").unwrap(), folder);
+
+		let file = drive.upload(Some(&folder), "file", b"some data", None).unwrap();
+		assert_eq!(drive.find_path(Some(&a), Path::new("b/file")).unwrap(), Some(file.clone()));
+		assert_eq!(drive.download(&file).unwrap(), b"some data");
+		assert!(drive.find_path(None, Path::new("/a/nothing")).unwrap().is_none());
+
+		let node = drive.stat(Some(&a), Path::new("b/file")).unwrap().unwrap();
+		assert_eq!(node.id, file);
+		assert_eq!(node.name, Some("file".to_owned()));
+		assert!(node.is_file());
+		assert_eq!(node.parents, vec![folder.clone()]);
+		assert_eq!(node.status, NodeStatus::Available);
+		assert_eq!(node.size, Some(9));
+		assert_eq!(node.md5, Some("1e50210a0202497fb79bc38b6ade6c34".to_owned()));
+		assert!(drive.get_node(&folder).unwrap().is_folder());
+		assert!(drive.stat(None, Path::new("/a/nothing")).unwrap().is_none());
+
+		match drive.upload(Some(&folder), "file", b"other data", None) {
+			Err(Error::NodeExists) => (),
+			_ => panic!("uploading over an existing name should fail with NodeExists"),
+		}
+
+		drive.upload(Some(&folder), "file2", b"more data", None).unwrap();
+		let mut names: Vec<String> = drive.ls(&folder).unwrap().into_iter().map(|x| x.name.unwrap()).collect();
+		names.sort();
+		assert_eq!(names, vec!["file", "file2"]);
+
+		let options = ListOptions::new().sort(SortField::Name, SortOrder::Descending);
+		let names: Vec<String> = drive.ls_with(&folder, &options).unwrap().into_iter().map(|x| x.name.unwrap()).collect();
+		assert_eq!(names, vec!["file2", "file"]);
+
+		let folders = drive.ls_with(&a, &ListOptions::new().kind(NodeKind::Folder)).unwrap();
+		assert_eq!(folders.len(), 1);
+		assert_eq!(folders[0].id, folder);
+		assert!(drive.ls_with(&a, &ListOptions::new().kind(NodeKind::File)).unwrap().is_empty());
+
+		drive.rm(&file).unwrap();
+		assert!(drive.find_path(None, Path::new("/a/b/file")).unwrap().is_none());
+		assert_eq!(drive.ls(&folder).unwrap().len(), 1);
+
+		// The name is free again once the old file is in the trash
+		let file = drive.upload(Some(&folder), "file", b"new data", None).unwrap();
+		assert_eq!(drive.download(&file).unwrap(), b"new data");
+	}
+
+	#[test]
+	fn test_memory_drive() {
+		let mut drive = MemoryDrive::new();
+		exercise(&mut drive);
+		assert_eq!(drive.trash().len(), 1);
+
+		let folder = drive.find_path(None, Path::new("/a/b")).unwrap().unwrap();
+		let trashed = drive.ls_with(&folder, &ListOptions::new().status(NodeStatus::Trash)).unwrap();
+		assert_eq!(trashed.len(), 1);
+		assert_eq!(trashed[0].name, Some("file".to_owned()));
+
+		match drive.download(&NodeId("nonexistent".to_owned())) {
+			Err(Error::NodeNotFound) => (),
+			_ => panic!("downloading a nonexistent node should fail with NodeNotFound"),
+		}
+	}
+
+	#[test]
+	fn test_local_drive() {
+		let temp_dir = TempDir::new("rust-acd-test").unwrap();
+		let mut drive = LocalDrive::new(temp_dir.path()).unwrap();
+		exercise(&mut drive);
+		assert!(drive.trash_dir().join("file").is_file());
+
+		// Ids can't escape the drive
+		match drive.download(&NodeId("/../../etc/passwd".to_owned())) {
+			Err(Error::NodeNotFound) => (),
+			_ => panic!("ids outside the drive should fail with NodeNotFound"),
+		}
+	}
+
+	#[test]
+	fn test_client_drive() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		exercise(&mut client);
+	}
+}
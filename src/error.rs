@@ -27,10 +27,22 @@ pub enum Error {
 	JsonDecoder(JsonDecoderError),
 	/// Url Parse Error
 	UrlParse(UrlParseError),
+	/// URL parsed, but isn't an http or https URL we can send requests to
+	BadUrl(String),
+	/// A request was put together in a way that can't be sent
+	BadRequest(String),
+	/// A value can't be used in a search `Filter`
+	BadFilter(String),
 	/// Need a new access token
 	ExpiredToken,
 	/// Bad Authentication URL
 	BadAuthUrl,
+	/// We have no authorization and the AuthPrompt declined to get one
+	NotAuthorized,
+	/// Tried to modify the Cloud Drive without having been granted the clouddrive:write scope
+	MissingWriteScope,
+	/// An EncryptedFileStore couldn't be decrypted; wrong passphrase or corrupted file
+	BadPassphrase,
 	/// Invalid path.  The path specified could not be parsed.
 	BadPath,
 	/// Server response was expected to be a string, but we couldn't decode it as UTF-8
@@ -43,9 +55,19 @@ pub enum Error {
 	ServerError(String),
 	/// Node (file/directory) exists
 	NodeExists,
+	/// Node doesn't exist, or isn't the kind of node (file/directory) the operation needs
+	NodeNotFound,
+	/// Node is a folder, but the operation needs a file
+	NotAFile,
+	/// A fixture file for `ReplayTransport` is malformed, or has no response for a request
+	BadFixture(String),
 	/// MD5 Mismatch during upload.  If this error is thrown, it means there was a mistmatch
 	/// and we failed to delete the file.  So the corrupt file is now on ACD.
 	MD5Mismatch,
+	/// Downloaded content didn't match the MD5 in the node's metadata
+	DownloadMD5Mismatch,
+	/// A file opened with `Client::open` was changed on the server while it was being read
+	NodeChanged,
 }
 
 impl fmt::Display for Error {
@@ -63,15 +85,26 @@ impl StdError for Error {
 			JsonEncoder(ref e) => e.description(),
 			JsonDecoder(ref e) => e.description(),
 			UrlParse(ref e) => e.description(),
+			BadUrl(_) => "URL must be an http or https URL",
+			BadRequest(ref e) => e,
+			BadFilter(ref e) => e,
 			ExpiredToken => "Access Token Expired",
 			BadPath => "Invalid path provided",
 			BadAuthUrl => "Invalid authorization URL provided",
+			NotAuthorized => "Not authorized to access Amazon Cloud Drive, and unable to prompt for authorization",
+			MissingWriteScope => "Client is not authorized to modify the Cloud Drive (missing clouddrive:write scope)",
+			BadPassphrase => "Unable to decrypt token store; wrong passphrase or corrupted file",
 			ResponseNotUtf8(_) => "Server response was supposed to be UTF-8, but wasn't",
 			ResponseBadJson(ref e) => e.description(),
 			UnknownServerError(ref e) => e,
 			ServerError(ref e) => e,
 			NodeExists => "Node exists",
+			NodeNotFound => "Node not found",
+			NotAFile => "Node is a folder, not a file",
+			BadFixture(ref e) => e,
 			MD5Mismatch => "MD5 Mismatch. This should never happen, so it looks like Amazon's server hit a bug.  Unable to correct the error!  The corrupted file was uploaded.",
+			DownloadMD5Mismatch => "MD5 of the downloaded content doesn't match the file's metadata",
+			NodeChanged => "File changed on the server while it was being read",
 		}
 	}
 
@@ -83,15 +116,26 @@ impl StdError for Error {
 			JsonEncoder(ref error) => Some(error),
 			JsonDecoder(ref error) => Some(error),
 			UrlParse(ref error) => Some(error),
+			BadUrl(_) => None,
+			BadRequest(_) => None,
+			BadFilter(_) => None,
 			ExpiredToken => None,
 			BadPath => None,
 			BadAuthUrl => None,
+			NotAuthorized => None,
+			MissingWriteScope => None,
+			BadPassphrase => None,
 			ResponseNotUtf8(_) => None,
 			ResponseBadJson(ref error) => Some(error),
 			UnknownServerError(_) => None,
 			ServerError(_) => None,
 			NodeExists => None,
+			NodeNotFound => None,
+			NotAFile => None,
+			BadFixture(_) => None,
 			MD5Mismatch => None,
+			DownloadMD5Mismatch => None,
+			NodeChanged => None,
 		}
 	}
 }
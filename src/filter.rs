@@ -0,0 +1,214 @@
+use std::fmt;
+use std::ops;
+use node::{NodeKind, NodeStatus};
+use error::{Result, Error};
+use super::NodeId;
+
+
+/// A query for `Client::search`, in the Lucene-like syntax of the API's `filters` parameter.
+/// Values are escaped as needed, so any name, label, etc. can be searched for.  Dates can't be,
+/// so they're checked instead.
+///
+/// ```
+/// use acd::{Filter, NodeKind};
+///
+/// // Files of at least 1 MB that were modified in 2016 and aren't labelled "backup"
+/// let filter = Filter::kind(NodeKind::File)
+///     .and(Filter::size(Some(1024 * 1024), None))
+///     .and(Filter::modified_date(Some("2016-01-01T00:00:00.000Z"), Some("2016-12-31T23:59:59.999Z")).unwrap())
+///     .and(!Filter::label("backup"));
+///
+/// assert_eq!(filter.to_string(), "kind:FILE AND contentProperties.size:[1048576 TO *] AND \
+///     modifiedDate:[2016-01-01T00:00:00.000Z TO 2016-12-31T23:59:59.999Z] AND NOT labels:backup");
+/// ```
+#[derive(Clone, PartialEq, Debug)]
+pub struct Filter(Expr);
+
+#[derive(Clone, PartialEq, Debug)]
+enum Expr {
+	Term(&'static str, String),
+	/// Inclusive at both ends; None is unbounded
+	Range(&'static str, Option<String>, Option<String>),
+	And(Vec<Filter>),
+	Or(Vec<Filter>),
+	Not(Box<Filter>),
+}
+
+impl Filter {
+	/// Nodes called `name`.
+	pub fn name(name: &str) -> Filter {
+		Filter::term("name", name)
+	}
+
+	pub fn kind(kind: NodeKind) -> Filter {
+		Filter::term("kind", kind.as_str())
+	}
+
+	pub fn status(status: NodeStatus) -> Filter {
+		Filter::term("status", status.as_str())
+	}
+
+	/// Files whose content has MD5 `md5` (as hex).
+	pub fn md5(md5: &str) -> Filter {
+		Filter::term("contentProperties.md5", &md5.to_lowercase())
+	}
+
+	/// Files of between `min` and `max` bytes, inclusive.  None leaves that end open.
+	pub fn size(min: Option<u64>, max: Option<u64>) -> Filter {
+		Filter::range("contentProperties.size", min.map(|min| min.to_string()), max.map(|max| max.to_string()))
+	}
+
+	/// Nodes created between `after` and `before` (RFC 3339, like `Node::created_date`), inclusive.
+	/// None leaves that end open.  Dates can't be escaped, so anything but ASCII letters, digits,
+	/// `-`, `:`, `.` and `+` fails with `Error::BadFilter`.
+	pub fn created_date(after: Option<&str>, before: Option<&str>) -> Result<Filter> {
+		Ok(Filter::range("createdDate", try!(check_date(after)), try!(check_date(before))))
+	}
+
+	/// Nodes last modified between `after` and `before` (RFC 3339, like `Node::modified_date`),
+	/// inclusive.  None leaves that end open.  Fails with `Error::BadFilter` like `created_date`.
+	pub fn modified_date(after: Option<&str>, before: Option<&str>) -> Result<Filter> {
+		Ok(Filter::range("modifiedDate", try!(check_date(after)), try!(check_date(before))))
+	}
+
+	/// Nodes with label `label`.
+	pub fn label(label: &str) -> Filter {
+		Filter::term("labels", label)
+	}
+
+	/// Nodes in folder `parent`.
+	pub fn parent(parent: &NodeId) -> Filter {
+		Filter::term("parents", &parent.0)
+	}
+
+	/// The root folder (or everything else, if `is_root` is false).
+	pub fn is_root(is_root: bool) -> Filter {
+		Filter::term("isRoot", if is_root { "true" } else { "false" })
+	}
+
+	/// Nodes matching both `self` and `other`.
+	pub fn and(self, other: Filter) -> Filter {
+		match self.0 {
+			Expr::And(mut filters) => {
+				filters.push(other);
+				Filter(Expr::And(filters))
+			},
+			expr => Filter(Expr::And(vec![Filter(expr), other])),
+		}
+	}
+
+	/// Nodes matching `self`, `other`, or both.
+	pub fn or(self, other: Filter) -> Filter {
+		match self.0 {
+			Expr::Or(mut filters) => {
+				filters.push(other);
+				Filter(Expr::Or(filters))
+			},
+			expr => Filter(Expr::Or(vec![Filter(expr), other])),
+		}
+	}
+
+	fn term(field: &'static str, value: &str) -> Filter {
+		Filter(Expr::Term(field, value.to_owned()))
+	}
+
+	fn range(field: &'static str, min: Option<String>, max: Option<String>) -> Filter {
+		Filter(Expr::Range(field, min, max))
+	}
+
+	fn is_compound(&self) -> bool {
+		match self.0 {
+			Expr::And(_) | Expr::Or(_) => true,
+			_ => false,
+		}
+	}
+}
+
+/// Nodes not matching the filter.
+impl ops::Not for Filter {
+	type Output = Filter;
+
+	fn not(self) -> Filter {
+		Filter(Expr::Not(Box::new(self)))
+	}
+}
+
+impl fmt::Display for Filter {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.0 {
+			Expr::Term(field, ref value) => write!(f, "{}:{}", field, escape(value)),
+			Expr::Range(field, ref min, ref max) => {
+				let bound = |bound: &Option<String>| bound.clone().unwrap_or_else(|| "*".to_owned());
+				write!(f, "{}:[{} TO {}]", field, bound(min), bound(max))
+			},
+			Expr::And(ref filters) => write_joined(f, filters, " AND "),
+			Expr::Or(ref filters) => write_joined(f, filters, " OR "),
+			Expr::Not(ref filter) => {
+				try!(f.write_str("NOT "));
+				write_operand(f, filter)
+			},
+		}
+	}
+}
+
+
+/// Backslash-escape the characters that mean something in a filter value (as listed in Amazon's
+/// docs), and whitespace.
+pub fn escape(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+
+	for c in value.chars() {
+		match c {
+			'+' | '-' | '&' | '|' | '!' | '(' | ')' | '{' | '}' | '[' | ']' | '^' | '\'' | '"' | '~' | '*' | '?' | ':' | '\\' => escaped.push('\\'),
+			c if c.is_whitespace() => escaped.push('\\'),
+			_ => (),
+		}
+		escaped.push(c);
+	}
+
+	escaped
+}
+
+
+/// Range bounds go into the filter as they are: Amazon's filter docs only ever show bare numbers
+/// and timestamps as bounds, and say nothing about quoting or escaping them.  So rather than
+/// guess, a date with anything a timestamp wouldn't have is refused; whitespace, `]` or `}`
+/// would end the bound early.
+fn check_date(date: Option<&str>) -> Result<Option<String>> {
+	match date {
+		Some(date) => {
+			let safe = date.chars().all(|c| match c {
+				'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | ':' | '.' | '+' => true,
+				_ => false,
+			});
+
+			if !safe || date.is_empty() || date == "TO" {
+				return Err(Error::BadFilter(format!("{:?} is not a date that can be used in a filter", date)));
+			}
+			Ok(Some(date.to_owned()))
+		},
+		None => Ok(None),
+	}
+}
+
+
+fn write_joined(f: &mut fmt::Formatter, filters: &[Filter], separator: &str) -> fmt::Result {
+	for (i, filter) in filters.iter().enumerate() {
+		if i > 0 {
+			try!(f.write_str(separator));
+		}
+		try!(write_operand(f, filter));
+	}
+
+	Ok(())
+}
+
+
+/// Write `filter`, in parentheses if it's an AND or OR.
+fn write_operand(f: &mut fmt::Formatter, filter: &Filter) -> fmt::Result {
+	if filter.is_compound() {
+		write!(f, "({})", filter)
+	} else {
+		write!(f, "{}", filter)
+	}
+}
@@ -1,5 +1,4 @@
 extern crate hyper;
-extern crate multipart;
 extern crate url;
 #[macro_use]
 extern crate mime;
@@ -9,28 +8,48 @@ extern crate crypto;
 extern crate rusqlite;
 extern crate tempdir;
 extern crate rand;
+extern crate fs2;
 
 mod rest;
 mod error;
+mod auth;
+mod store;
+mod builder;
+mod drive;
+mod transport;
+mod node;
+mod list;
+mod filter;
+mod remote;
+mod content_type;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 
 pub use error::{Result, Error};
-
-use url::{Url, form_urlencoded};
-use std::process::Command;
+pub use auth::{AuthPrompt, ConsolePrompt, NonInteractivePrompt, Scope, DEFAULT_SCOPES, READ_ONLY_SCOPES};
+pub use store::{TokenStore, StoreLock, JsonFileStore, MemoryStore, EncryptedFileStore};
+pub use builder::{ClientBuilder, DEFAULT_AUTHORIZE_URL, DEFAULT_TOKEN_URL, DEFAULT_ENDPOINT_URL};
+pub use drive::{CloudDrive, MemoryDrive, LocalDrive};
+pub use node::{Node, NodeKind, NodeStatus};
+pub use list::{ListOptions, SortField, SortOrder, NodeIter};
+pub use filter::Filter;
+pub use remote::{RemoteFile, DEFAULT_READ_AHEAD};
+pub use transport::{Transport, BodySink, HttpRequest, HttpResponse, HyperTransport, RecordingTransport, ReplayTransport};
+
+use url::form_urlencoded;
 use std::io::{self, Read, Write};
 use rustc_serialize::{json, Decodable, Encodable};
 use std::fs::{self, File};
 use time::Timespec;
-use std::path::{Path, Component};
-use rest::RestBuilder;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use rest::{RestBuilder, RequestSettings};
+use node::NodeJson;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use hyper::status::StatusCode;
 use crypto::md5::Md5;
 use crypto::digest::Digest;
-use hyper::http;
-use hyper::client::pool::Pool;
-use std::path::PathBuf;
 use std::str;
+use std::result;
 use rand::Rng;
 use std::cmp;
 
@@ -38,43 +57,134 @@ use std::cmp;
 /// How many hours to hold onto an endpoint (after which the endpoint is refreshed)
 const REFRESH_ENDPOINT_TIME: i64 = 3*24;
 
+/// How many seconds before an access token expires we go ahead and refresh it
+const REFRESH_AUTHORIZATION_MARGIN: i64 = 5*60;
+
+/// The property `Client::upload_file` stores a file's local modification time in.
+pub const MTIME_PROPERTY: &'static str = "mtime";
+
+/// Where Amazon sends the user's browser after they authorize us.  We listen on this address
+/// to catch the authorization code.
+const REDIRECT_URI: &'static str = "http://localhost:26619/";
+
 
 pub struct Client {
-	config_dir: PathBuf,
 	security_profile: SecurityProfile,
 	authorization: Authorization,
 	endpoint: Endpoint,
+	/// True if the endpoint was given to us, rather than looked up from Amazon
+	fixed_endpoint: bool,
 	root_id: NodeId,
+	/// True once we've authorized, refreshed the endpoint, and found root_id
+	connected: bool,
 	cache_connection: rusqlite::Connection,
-	protocol: Box<http::Protocol>,
+	/// None if the cache is in memory
+	cache_path: Option<PathBuf>,
+	transport: Box<Transport>,
+	request_settings: RequestSettings,
+	auth_prompt: Box<AuthPrompt>,
+	token_store: Box<TokenStore>,
 	/// How many times we retry contacting Amazon after a server error
 	maximum_retry: u32,
+	/// Upper bound of the random wait before the first retry; doubles for each retry after that
+	retry_backoff: Duration,
+	/// Amazon's login page
+	authorize_url: String,
+	/// OAuth token endpoint
+	token_url: String,
+	/// Where we look up the account's metadata and content URLs
+	endpoint_url: String,
+	/// OAuth token revocation endpoint (RFC 7009) to notify on logout, if any
+	revocation_url: Option<String>,
+	/// The OAuth scopes we ask for
+	scopes: Vec<Scope>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct NodeId(String);
 
+/// What `Client::upload_unless_exists` did.
+#[derive(Clone, PartialEq, Debug)]
+pub enum UploadStatus {
+	/// The file was uploaded as a new node
+	Uploaded(NodeId),
+	/// An identical file of the same name was already in the folder, so nothing was sent
+	AlreadyExists(Node),
+}
+
+impl UploadStatus {
+	/// The id of the file, whether it was uploaded or already there.
+	pub fn id(&self) -> &NodeId {
+		match *self {
+			UploadStatus::Uploaded(ref id) => id,
+			UploadStatus::AlreadyExists(ref node) => &node.id,
+		}
+	}
+}
+
 #[derive(RustcEncodable, RustcDecodable)]
 struct SecurityProfile {
 	pub client_id: String,
 	pub client_secret: String,
 }
 
-#[derive(RustcEncodable, RustcDecodable)]
-struct Authorization {
+/// OAuth tokens granting us access to the user's Cloud Drive.  Persisted by a `TokenStore`.
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+pub struct Authorization {
 	pub access_token: String,
 	pub refresh_token: String,
 	pub token_type: String,
 	pub date_last_updated: i64,
+	/// When the access token expires (seconds since the epoch).  None for authorizations saved
+	/// before we started tracking expiry; those are refreshed when the server rejects them.
+	pub expires_at: Option<i64>,
+	/// The OAuth scopes this authorization was granted.  None for authorizations saved before
+	/// we started tracking scopes; those were always granted DEFAULT_SCOPES.
+	pub scopes: Option<Vec<String>>,
 }
 
-#[derive(RustcEncodable, RustcDecodable)]
-struct Endpoint {
+/// The account specific URLs that all metadata and content requests go to.  Persisted by a `TokenStore`.
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+pub struct Endpoint {
 	pub content_url: String,
 	pub metadata_url: String,
 	pub date_last_updated: i64,
 }
 
+impl Authorization {
+	/// True if the access token expires within REFRESH_AUTHORIZATION_MARGIN.  Tokens that were
+	/// granted for less than twice that are refreshed halfway through their lifetime instead, so
+	/// that we don't end up refreshing before every request.
+	fn expires_soon(&self) -> bool {
+		match self.expires_at {
+			Some(expires_at) => {
+				let margin = cmp::min(REFRESH_AUTHORIZATION_MARGIN, (expires_at - self.date_last_updated) / 2);
+				time::get_time().sec + margin >= expires_at
+			},
+			None => false,
+		}
+	}
+
+	/// True if this authorization was granted `scope`.
+	fn has_scope(&self, scope: Scope) -> bool {
+		match self.scopes {
+			Some(ref scopes) => scopes.iter().any(|s| s == scope.as_str()),
+			None => DEFAULT_SCOPES.contains(&scope),
+		}
+	}
+
+	/// True if this authorization was granted `scopes` and nothing else.  A client that asked for
+	/// less (e.g. READ_ONLY_SCOPES) must not quietly pick up a broader grant saved by another.
+	fn has_exactly_scopes(&self, scopes: &[Scope]) -> bool {
+		let granted: Vec<&str> = match self.scopes {
+			Some(ref granted) => granted.iter().map(|scope| &scope[..]).collect(),
+			None => DEFAULT_SCOPES.iter().map(|scope| scope.as_str()).collect(),
+		};
+
+		scopes.iter().all(|&scope| self.has_scope(scope)) && granted.iter().all(|&granted| scopes.iter().any(|scope| scope.as_str() == granted))
+	}
+}
+
 #[derive(RustcDecodable, Debug)]
 struct O2TokenResponse {
 	pub access_token: String,
@@ -99,61 +209,99 @@ impl Client {
 	/// When creating a new instance without any pre-existing configuration, the user will be
 	/// prompted to give access to their Amazon Cloud Drive account.  The authorization will be
 	/// saved to the config_dir so it can be re-used in the future and not prompt the user again.
+	///
+	/// For more control (timeouts, token storage, non-interactive authorization, etc) use `ClientBuilder`.
 	pub fn new<P: AsRef<Path>>(client_id: &str, client_secret: &str, config_dir: P, maximum_retry: u32) -> Result<Client> {
-		let config_dir = config_dir.as_ref().join(".acd");
-
-		// Create configuration directory
-		try!(fs::create_dir_all(&config_dir));
+		ClientBuilder::new(client_id, client_secret)
+			.config_dir(config_dir)
+			.maximum_retry(maximum_retry)
+			.build()
+	}
 
-		let cache_conn = try!(Client::init_cache(&config_dir));
+	/// Authorize (if needed), look up our endpoint and find the root folder.
+	/// Clients built with `ClientBuilder::lazy` do this on first use; call it directly to get
+	/// any authorization or connection problems out of the way up front.
+	pub fn connect(&mut self) -> Result<()> {
+		if self.connected {
+			return Ok(());
+		}
 
-		let security_profile = SecurityProfile {
-			client_id: client_id.to_owned(),
-			client_secret: client_secret.to_owned(),
-		};
+		// If we aren't authorized yet (or not for exactly what we want), authorize.
+		if self.authorization.access_token.is_empty() || !self.authorization.has_exactly_scopes(&self.scopes) {
+			try!(self.authorize());
+		}
 
-		// Read existing endpoint or start from scratch.
-		let endpoint = read_json_file(config_dir.join("endpoint.json")).unwrap_or(Endpoint {
-			content_url: String::new(),
-			metadata_url: String::new(),
-			date_last_updated: 0,
-		});
+		// TODO: Technically we need to call refresh_endpoint before every REST call that uses
+		// the endpoint, so that if the library is run for a long time (3+ days) it'll follow spec.
+		// Right now this should be good enough, and it's unlikely that the endpoint will change anyway.
+		try!(self.refresh_endpoint());
+		self.root_id = try!(self.find_root());
+		self.connected = true;
 
-		// Read existing authorization or start from scratch.
-		let authorization = read_json_file(config_dir.join("authorization.json")).unwrap_or(Authorization {
-			access_token: String::new(),
-			refresh_token: String::new(),
-			token_type: String::new(),
-			date_last_updated: 0,
-		});
+		Ok(())
+	}
 
-		let mut acd = Client {
-			config_dir: config_dir,
-			security_profile: security_profile,
-			authorization: authorization,
-			endpoint: endpoint,
-			root_id: NodeId(String::new()),
-			cache_connection: cache_conn,
-			protocol: Box::new(http::h1::Http11Protocol::with_connector(Pool::new(Default::default()))),
-			maximum_retry: maximum_retry,
+	/// Log out: revoke our refresh token (if a revocation endpoint is configured) and remove the
+	/// stored Authorization and Endpoint from the TokenStore.  If `remove_cache` is true, the node
+	/// cache is deleted as well.  The local credentials are removed even if revocation fails.
+	pub fn logout(mut self, remove_cache: bool) -> Result<()> {
+		let revocation_result = match self.revocation_url.clone() {
+			Some(ref revocation_url) if !self.authorization.refresh_token.is_empty() => self.revoke_authorization(revocation_url),
+			_ => Ok(()),
 		};
 
-		// If we aren't authorized yet, authorize.
-		if acd.authorization.access_token.is_empty() {
-			try!(acd.authorize());
+		{
+			let _lock = try!(self.token_store.lock());
+			try!(self.token_store.clear());
 		}
 
-		// TODO: Technically we need to call refresh_endpoint before every REST call that uses
-		// the endpoint, so that if the library is run for a long time (3+ days) it'll follow spec.
-		// Right now this should be good enough, and it's unlikely that the endpoint will change anyway.
-		try!(acd.refresh_endpoint());
-		acd.root_id = try!(acd.find_root());
+		if remove_cache {
+			let Client { cache_connection, cache_path, .. } = self;
+			drop(cache_connection);
 
-		Ok(acd)
+			// SQLite may have left a rollback journal or write-ahead log next to the database
+			if let Some(cache_path) = cache_path {
+				for suffix in &["", "-journal", "-wal", "-shm"] {
+					let mut path = cache_path.clone().into_os_string();
+					path.push(suffix);
+					try!(store::remove_file_if_exists(path));
+				}
+			}
+		}
+
+		revocation_result
 	}
 
-	fn init_cache<P: AsRef<Path>>(config_dir: P) -> Result<rusqlite::Connection> {
-		let conn = try!(rusqlite::Connection::open(config_dir.as_ref().join("cache.sqlite")));
+	fn revoke_authorization(&mut self, revocation_url: &str) -> Result<()> {
+		let request = try!(RestBuilder::post(revocation_url))
+			.body_query(&[
+				("token_type_hint", "refresh_token"),
+				("token", &self.authorization.refresh_token),
+				("client_id", &self.security_profile.client_id),
+				("client_secret", &self.security_profile.client_secret)
+			]);
+		let (status_code, body) = try!(self.get_server_response_with_retry(request, false));
+
+		match status_code {
+			StatusCode::Ok => Ok(()),
+			_ => Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
+		}
+	}
+
+	/// Fail fast if we weren't asked for, or weren't granted, write access.
+	fn check_write_scope(&self) -> Result<()> {
+		if self.scopes.contains(&Scope::Write) && self.authorization.has_scope(Scope::Write) {
+			Ok(())
+		} else {
+			Err(Error::MissingWriteScope)
+		}
+	}
+
+	fn init_cache(cache_path: Option<&Path>) -> Result<rusqlite::Connection> {
+		let conn = match cache_path {
+			Some(cache_path) => try!(rusqlite::Connection::open(cache_path)),
+			None => try!(rusqlite::Connection::open_in_memory()),
+		};
 
 		// Set up tables if they don't exist
 		try!(conn.execute("CREATE TABLE IF NOT EXISTS path_cache (
@@ -164,6 +312,9 @@ impl Client {
 		try!(conn.execute("CREATE INDEX IF NOT EXISTS idx_path_cache_parent_name ON path_cache (parent, name);", &[]));
 		try!(conn.execute("CREATE INDEX IF NOT EXISTS idx_path_cache_parent ON path_cache (parent);", &[]));
 
+		// Other instances may share our cache, so wait on their locks instead of failing immediately
+		try!(conn.execute_batch("PRAGMA busy_timeout = 10000;"));
+
 		Ok(conn)
 	}
 
@@ -172,6 +323,11 @@ impl Client {
 		Ok(())
 	}
 
+	fn remove_from_node_cache(&mut self, &NodeId(ref id): &NodeId) -> Result<()> {
+		try!(self.cache_connection.execute("DELETE FROM path_cache WHERE id=?", &[&id.to_owned()]));
+		Ok(())
+	}
+
 	fn fetch_from_node_cache(&self, &NodeId(ref parent): &NodeId, name: &str) -> Result<Option<NodeId>> {
 		let result = self.cache_connection.query_row("SELECT id FROM path_cache WHERE parent=? AND name=?", &[&parent.to_owned(), &name.to_owned()], |row| {
         	NodeId(row.get(0))
@@ -186,11 +342,25 @@ impl Client {
 
 	// Make the request to the server and get the response.
 	// If there's a server error, try again using the recommended backoff method.
-	// If our access token has expired, we will attempt renew it.
+	// If our access token is about to expire, or has expired, we will attempt renew it.
 	fn get_server_response_with_retry(&mut self, rest: RestBuilder, authorize: bool) -> Result<(StatusCode, Vec<u8>)> {
+		self.send_with_retry(rest, authorize, |client, rest| Ok(try!(rest.send(&mut *client.transport, &client.request_settings))))
+	}
+
+	// Like get_server_response_with_retry, but the request is sent by `send`, which is called again
+	// for every retry.  `SendError::Fatal` errors are returned at once, without retrying.
+	fn send_with_retry<F>(&mut self, rest: RestBuilder, authorize: bool, mut send: F) -> Result<(StatusCode, Vec<u8>)>
+		where F: FnMut(&mut Client, RestBuilder) -> result::Result<HttpResponse, SendError>
+	{
 		let mut retry_count = 0;
 
 		loop {
+			// Refresh ahead of time, rather than waste a request finding out the token expired.
+			// The ExpiredToken handling below remains as a fallback (e.g. for clock skew).
+			if authorize && self.authorization.expires_soon() {
+				try!(self.refresh_authorization());
+			}
+
 			let rest_copy = rest.clone();
 			let rest_copy = if authorize {
 				rest_copy.authorization(&(self.authorization.access_token.clone()))
@@ -199,12 +369,20 @@ impl Client {
 			};
 
 			// Backoff
-			if retry_count > 0 {
-				let backoff = rand::thread_rng().gen_range(0, 1000 * (1 << (cmp::min(retry_count - 1, 8))));
+			let backoff_ms = self.retry_backoff.as_secs() * 1000 + (self.retry_backoff.subsec_nanos() / 1000000) as u64;
+			if retry_count > 0 && backoff_ms > 0 {
+				let backoff = rand::thread_rng().gen_range(0, backoff_ms.saturating_mul(1 << cmp::min(retry_count - 1, 8)));
 				std::thread::sleep(Duration::from_millis(backoff));
 			}
 
-			let (status_code, body) = match self.get_server_response(rest_copy) {
+			let response = match send(self, rest_copy) {
+				Ok(response) => Ok(response),
+				Err(SendError::Remote(err)) => Err(err),
+				// Retrying won't help, e.g. when the file to upload is missing
+				Err(SendError::Fatal(err)) => return Err(err),
+			};
+
+			let (status_code, body) = match response.and_then(Client::check_server_response) {
 				Ok((status_code, body)) => (status_code, body),
 				Err(Error::ExpiredToken) => if authorize {
 					// Need reauthentication
@@ -215,6 +393,10 @@ impl Client {
 					// Server told us our access token was expired, but we didn't provide one...
 					return Err(Error::ServerError(format!("Server reported Expired Token on a call that didn't have a token.")));
 				},
+				// Retrying won't make a response appear in the fixture
+				Err(Error::BadFixture(err)) => return Err(Error::BadFixture(err)),
+				// Nor will it fix a badly built request
+				Err(Error::BadRequest(err)) => return Err(Error::BadRequest(err)),
 				Err(err) => {
 					// Communication error, retry
 					retry_count += 1;
@@ -242,20 +424,18 @@ impl Client {
 		}
 	}
 
-	// Make the request to the server and get the response.
-	fn get_server_response(&mut self, rest: RestBuilder) -> Result<(StatusCode, Vec<u8>)> {
+	// Check the server's response for an expired token.
+	fn check_server_response(response: HttpResponse) -> Result<(StatusCode, Vec<u8>)> {
 		#[derive(RustcDecodable, Debug)]
 		struct MessageResponse {
 			message: String,
 		}
 
-		let mut response = try!(rest.send(&self.protocol));
-
-		let mut body = vec![0u8; 0];
-		try!(response.read_to_end(&mut body));
+		let status = StatusCode::from_u16(response.status);
+		let body = response.body;
 
-		if response.status.is_success() {
-			return Ok((response.status, body));
+		if status.is_success() {
+			return Ok((status, body));
 		}
 
 		// Errors usually have some JSON error message associated with them
@@ -277,7 +457,7 @@ impl Client {
 			}
 		}
 
-		Ok((response.status, body))
+		Ok((status, body))
 	}
 
 	fn refresh_endpoint(&mut self) -> Result<()> {
@@ -288,6 +468,10 @@ impl Client {
 			metadataUrl: String,
 		}
 
+		if self.fixed_endpoint {
+			return Ok(())
+		}
+
 		let date_last_updated = Timespec::new(self.endpoint.date_last_updated, 0);
 		let now = time::get_time();
 
@@ -295,7 +479,7 @@ impl Client {
 			return Ok(())
 		}
 
-		let request = RestBuilder::get("https://drive.amazonaws.com/drive/v1/account/endpoint");
+		let request = try!(RestBuilder::get(&self.endpoint_url));
 		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
 
 		let response: AccountEndpointResponse = match status_code {
@@ -311,41 +495,47 @@ impl Client {
 			date_last_updated: time::get_time().sec,
 		};
 
-		try!(write_json_file(self.config_dir.join("endpoint.json"), &self.endpoint));
+		{
+			let _lock = try!(self.token_store.lock());
+			try!(self.token_store.save_endpoint(&self.endpoint));
+		}
 
 		Ok(())
 	}
 
 	fn authorize(&mut self) -> Result<()> {
-		/* First, direct the user to the Amazon login page */
-		open_webbrowser(&("https://www.amazon.com/ap/oa?".to_string() + &form_urlencoded::serialize(&[
+		// Hold the lock for the whole process, so concurrent instances sharing our store wait
+		// for us rather than asking the user to authorize twice.
+		let _lock = try!(self.token_store.lock());
+
+		if let Some(stored) = try!(self.token_store.load_authorization()) {
+			if !stored.access_token.is_empty() && stored.has_exactly_scopes(&self.scopes) {
+				self.authorization = stored;
+				return Ok(());
+			}
+		}
+
+		let scopes: Vec<String> = self.scopes.iter().map(|scope| scope.as_str().to_owned()).collect();
+
+		let authorization_url = self.authorize_url.clone() + "?" + &form_urlencoded::serialize(&[
 			("client_id", &self.security_profile.client_id),
-			("scope", &"clouddrive:read_all clouddrive:write".to_owned()),
+			("scope", &scopes.join(" ")),
 			("response_type", &"code".to_owned()),
-			("redirect_uri", &"http://localhost:26619/".to_owned())
-		])));
-
-		/* After they login, their browser will redirect to the authorization URL which contains the
-		 * code we need.  The user should copy the URL from their browser and paste it into the console
-		 */
-		println!("Paste the response url:");
-		let code = {
-			let mut response_url = String::new();
-			try!(io::stdin().read_line(&mut response_url));
-
-			let response_pairs = try!(try!(Url::parse(&response_url)).query_pairs().ok_or(Error::BadAuthUrl));
-			let code = try!(response_pairs.iter().find(|&x| x.0 == "code").ok_or(Error::BadAuthUrl));
-			&code.1.clone()
-		};
+			("redirect_uri", &REDIRECT_URI.to_owned())
+		]);
+
+		/* Have the user log in and authorize us, which gets us a code */
+		let code = try!(self.auth_prompt.authorize(&authorization_url, REDIRECT_URI));
+		let code = &code;
 
 		/* Get authorization tokens from Amazon using the code */
-		let request = RestBuilder::post("https://api.amazon.com/auth/o2/token")
+		let request = try!(RestBuilder::post(&self.token_url))
 			.body_query(&[
 				("grant_type", "authorization_code"),
 				("code", code),
 				("client_id", &self.security_profile.client_id),
 				("client_secret", &self.security_profile.client_secret),
-				("redirect_uri", "http://localhost:26619/")
+				("redirect_uri", REDIRECT_URI)
 			]);
 		let (status_code, body) = try!(self.get_server_response_with_retry(request, false));
 
@@ -355,29 +545,46 @@ impl Client {
 				},
 				_ => return Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
 			};
+		let now = time::get_time().sec;
 
 		self.authorization = Authorization {
 			access_token: response.access_token,
 			refresh_token: response.refresh_token,
 			token_type: response.token_type,
-			date_last_updated: time::get_time().sec,
+			date_last_updated: now,
+			expires_at: Some(now + response.expires_in as i64),
+			scopes: Some(scopes),
 		};
 
-		try!(write_json_file(self.config_dir.join("authorization.json"), &self.authorization));
+		try!(self.token_store.save_authorization(&self.authorization));
 
 		Ok(())
 	}
 
 	fn refresh_authorization(&mut self) -> Result<()> {
+		let _lock = try!(self.token_store.lock());
+
+		// Another process sharing our store may have refreshed while we were waiting for the lock.
+		// If so, use its tokens; refreshing again would invalidate the refresh token it just saved.
+		if let Some(stored) = try!(self.token_store.load_authorization()) {
+			if stored.access_token != self.authorization.access_token {
+				self.authorization = stored;
+
+				if !self.authorization.expires_soon() {
+					return Ok(());
+				}
+			}
+		}
+
 		println!("Refreshing authorization");
 
-		let request = RestBuilder::post("https://api.amazon.com/auth/o2/token")
+		let request = try!(RestBuilder::post(&self.token_url))
 			.body_query(&[
 				("grant_type", "refresh_token"),
 				("refresh_token", &self.authorization.refresh_token),
 				("client_id", &self.security_profile.client_id),
 				("client_secret", &self.security_profile.client_secret),
-				("redirect_uri", "http://localhost:26619/")
+				("redirect_uri", REDIRECT_URI)
 			]);
 		let (status_code, body) = try!(self.get_server_response_with_retry(request, false));
 
@@ -387,15 +594,18 @@ impl Client {
 			},
 			_ => return Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
 		};
+		let now = time::get_time().sec;
 
 		self.authorization = Authorization {
 			access_token: response.access_token,
 			refresh_token: response.refresh_token,
 			token_type: response.token_type,
-			date_last_updated: time::get_time().sec,
+			date_last_updated: now,
+			expires_at: Some(now + response.expires_in as i64),
+			scopes: self.authorization.scopes.clone(),
 		};
 
-		try!(write_json_file(self.config_dir.join("authorization.json"), &self.authorization));
+		try!(self.token_store.save_authorization(&self.authorization));
 
 		Ok(())
 	}
@@ -414,9 +624,9 @@ impl Client {
 			data: [NodeResponse; 1],
 		}
 
-		let request = RestBuilder::get(&self.endpoint.metadata_url.clone())
+		let request = try!(RestBuilder::get(&self.endpoint.metadata_url.clone()))
 			.url_push("nodes")
-			.url_query(&[("filters", "kind:FOLDER AND isRoot:true")]);
+			.url_query(&[("filters", Filter::kind(NodeKind::Folder).and(Filter::is_root(true)).to_string())]);
 
 		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
 
@@ -443,15 +653,17 @@ impl Client {
 			data: Vec<NodeResponse>,
 		}
 
+		try!(self.connect());
+
 		if let Some(id) = try!(self.fetch_from_node_cache(parent, name)) {
 			return Ok(Some(id));
 		}
 
-		let request = RestBuilder::get(&self.endpoint.metadata_url)
+		let request = try!(RestBuilder::get(&self.endpoint.metadata_url))
 			.url_push("nodes")
 			.url_push(&parent.0)
 			.url_push("children")
-			.url_query(&[("filters", "name:".to_owned() + name)]);
+			.url_query(&[("filters", Filter::name(name).to_string())]);
 		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
 
 		match status_code {
@@ -463,6 +675,7 @@ impl Client {
 				try!(self.insert_into_node_cache(parent, name, &response.data[0].id));
 				Ok(Some(NodeId(response.data[0].id.clone())))
 			},
+			StatusCode::NotFound => Err(Error::NodeNotFound),
 			_ => return Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
 		}
 	}
@@ -470,30 +683,48 @@ impl Client {
 	/// Find a node using an absolute or relative path.
 	/// Returns None if the path could not be found.
 	pub fn find_path<P: AsRef<Path>>(&mut self, parent: Option<&NodeId>, path: P) -> Result<Option<NodeId>> {
-		let mut current_dir = parent.unwrap_or(&self.root_id).clone();
-
-		for p in path.as_ref().components() {
-			match p {
-				Component::RootDir => current_dir = self.root_id.clone(),
-				Component::CurDir => (),
-				Component::Normal(osstr) => match osstr.to_str() {
-					Some(name) => current_dir = match try!(self.find_child(&current_dir, name)) {
-						Some(child) => child,
-						None => return Ok(None),
-					},
-					None => return Err(Error::BadPath),
-				},
-				_ => return Err(Error::BadPath),
-			}
+		drive::find_path(self, parent, path.as_ref())
+	}
+
+	/// Metadata for node `id`.
+	pub fn get_node(&mut self, id: &NodeId) -> Result<Node> {
+		try!(self.connect());
+
+		let request = try!(RestBuilder::get(&self.endpoint.metadata_url))
+			.url_push("nodes")
+			.url_push(&id.0);
+		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
+
+		match status_code {
+			StatusCode::Ok => {
+				let response: NodeJson = try!(decode_server_json(&body));
+				Ok(response.into_node())
+			},
+			StatusCode::NotFound => Err(Error::NodeNotFound),
+			_ => Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
 		}
+	}
 
-		Ok(Some(current_dir))
+	/// Metadata for the node at an absolute or relative path.
+	/// Returns None if the path could not be found.
+	pub fn stat<P: AsRef<Path>>(&mut self, parent: Option<&NodeId>, path: P) -> Result<Option<Node>> {
+		drive::stat(self, parent, path.as_ref())
 	}
 
 	/// Upload `data` to ACD with filename `name` under parent `parent`.  The NodeId for the new file
 	/// is returned.  If we return successfully, the file is guaranteed to have been uploaded without
 	/// corruption, at least within the guarantees provided by Amazon Cloud Drive.
 	pub fn upload(&mut self, parent: Option<&NodeId>, name: &str, data: &[u8], content_type: Option<mime::Mime>) -> Result<NodeId> {
+		self.upload_reader(parent, name, Some(data.len() as u64), content_type, || Ok(data))
+	}
+
+	/// Like `upload`, but the content is streamed from a reader rather than held in memory.
+	/// `open` is called to get the reader, and called again for each retry, so it must return
+	/// the content from the start every time.  `length` is the exact length of the content, if
+	/// known; otherwise the upload is sent chunked.
+	pub fn upload_reader<F, R>(&mut self, parent: Option<&NodeId>, name: &str, length: Option<u64>, content_type: Option<mime::Mime>, mut open: F) -> Result<NodeId>
+		where F: FnMut() -> io::Result<R>, R: Read
+	{
 		#[derive(RustcEncodable)]
 		struct UploadMetadata {
 			name: String,
@@ -513,11 +744,8 @@ impl Client {
 			contentProperties: NodeUploadResponseContentProperties,
 		}
 
-		let calculated_md5 = {
-			let mut md5 = Md5::new();
-			md5.input(data);
-			md5.result_str().to_lowercase()
-		};
+		try!(self.connect());
+		try!(self.check_write_scope());
 
 		let parent = parent.unwrap_or(&self.root_id).clone();
 
@@ -527,15 +755,42 @@ impl Client {
 			parents: vec![parent.0.clone()],
 		};
 
-		let content_type = content_type.unwrap_or("application/octect-stream".parse().unwrap());
+		let content_type = content_type.unwrap_or(content_type::DEFAULT_CONTENT_TYPE.parse().unwrap());
 
-		let request = RestBuilder::post(&self.endpoint.content_url)
+		let request = try!(RestBuilder::post(&self.endpoint.content_url))
 			.url_push("nodes")
 			.url_query(&[("suppress", "deduplication")])
 			.multipart_data("metadata", try!(json::encode(&metadata)).as_bytes(), None, None)
-			.multipart_data("content", data, Some(name.to_owned()), Some(content_type));
+			.multipart_stream("content", Some(name.to_owned()), Some(content_type));
 
-		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
+		// MD5 of what was sent by the last attempt
+		let mut calculated_md5 = String::new();
+
+		let (status_code, body) = try!(self.send_with_retry(request, true, |client, rest| {
+			let mut md5 = Md5::new();
+			let response = {
+				let source = try!(open().map_err(|err| SendError::Fatal(Error::Io(err))));
+				let mut content = Md5Reader::new(source, &mut md5);
+				let response = rest.send_streaming(&mut *client.transport, &client.request_settings, &mut content, length);
+
+				if response.is_err() {
+					if let Some(err) = content.error.take() {
+						return Err(SendError::Fatal(Error::Io(err)));
+					}
+
+					// `open` gave us less (or more) than `length`, and will again next time
+					if let Some(length) = length {
+						if content.count > length || (content.eof && content.count != length) {
+							return Err(SendError::Fatal(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("Upload content was {} bytes, expected {}", content.count, length)))));
+						}
+					}
+				}
+
+				try!(response)
+			};
+			calculated_md5 = md5.result_str().to_lowercase();
+			Ok(response)
+		}));
 
 		match status_code {
 			StatusCode::Created => {
@@ -558,6 +813,94 @@ impl Client {
 		}
 	}
 
+	/// Upload the local file at `local_path` as `name` in folder `parent` (or the root if None),
+	/// streaming it from disk.  The content type is guessed from `local_path`'s extension.
+	/// If `keep_mtime` is true, the file's local modification time (RFC 3339) is stored in the
+	/// node's `MTIME_PROPERTY` property; if that fails, the error is returned even though the file
+	/// was uploaded.
+	pub fn upload_file<P: AsRef<Path>>(&mut self, parent: Option<&NodeId>, local_path: P, name: &str, keep_mtime: bool) -> Result<NodeId> {
+		let local_path = local_path.as_ref();
+		let metadata = try!(fs::metadata(local_path));
+
+		if !metadata.is_file() {
+			return Err(Error::BadPath);
+		}
+
+		let content_type = content_type::from_path(local_path);
+		let id = try!(self.upload_reader(parent, name, Some(metadata.len()), Some(content_type), || File::open(local_path)));
+
+		if keep_mtime {
+			if let Some(mtime) = rfc3339(try!(metadata.modified())) {
+				try!(self.set_property(&id, MTIME_PROPERTY, &mtime));
+			}
+		}
+
+		Ok(id)
+	}
+
+	/// Set property `key` of node `id` to `value`.  Properties are stored under our client_id,
+	/// so other apps can't see them, and show up in `Node::properties`.
+	pub fn set_property(&mut self, id: &NodeId, key: &str, value: &str) -> Result<()> {
+		#[derive(RustcEncodable)]
+		struct Property {
+			value: String,
+		}
+
+		try!(self.connect());
+		try!(self.check_write_scope());
+
+		let property = Property {
+			value: value.to_owned(),
+		};
+
+		let request = try!(RestBuilder::put(&self.endpoint.metadata_url))
+			.url_push("nodes")
+			.url_push(&id.0)
+			.url_push("properties")
+			.url_push(&self.security_profile.client_id)
+			.url_push(key)
+			.body(try!(json::encode(&property)).as_bytes());
+
+		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
+
+		match status_code {
+			StatusCode::Ok | StatusCode::Created => Ok(()),
+			StatusCode::NotFound => Err(Error::NodeNotFound),
+			_ => Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
+		}
+	}
+
+	/// Like `upload`, but if folder `parent` already has a file called `name` with the same content
+	/// (going by MD5), that file is returned and nothing is uploaded.  A file called `name` with
+	/// different content still fails with `Error::NodeExists`.
+	pub fn upload_unless_exists(&mut self, parent: Option<&NodeId>, name: &str, data: &[u8], content_type: Option<mime::Mime>) -> Result<UploadStatus> {
+		try!(self.connect());
+		try!(self.check_write_scope());
+
+		let parent = parent.unwrap_or(&self.root_id).clone();
+		let filter = Filter::kind(NodeKind::File)
+			.and(Filter::md5(&md5_hex(data)))
+			.and(Filter::parent(&parent))
+			.and(Filter::name(name));
+		let existing = match try!(self.search(&filter)).next() {
+			Some(node) => Some(try!(node)),
+			None => None,
+		};
+
+		match existing {
+			Some(node) => {
+				try!(self.insert_into_node_cache(&parent, name, &node.id.0));
+				Ok(UploadStatus::AlreadyExists(node))
+			},
+			None => self.upload(Some(&parent), name, data, content_type).map(UploadStatus::Uploaded),
+		}
+	}
+
+	/// Every (available) file whose content has MD5 `md5` (as hex), wherever it is.
+	pub fn find_by_md5(&mut self, md5: &str) -> Result<Vec<Node>> {
+		try!(self.search(&Filter::kind(NodeKind::File).and(Filter::md5(md5)))).collect()
+	}
+
 	/// Create directory if it doesn't exist.
 	/// Returns id for created/existing directory.
 	/// If parent is None then parent will be the root node.
@@ -585,6 +928,9 @@ impl Client {
 			info: ConflictResponseInfo,
 		}
 
+		try!(self.connect());
+		try!(self.check_write_scope());
+
 		let parent = parent.unwrap_or(&self.root_id).clone();
 
 		if let Some(id) = try!(self.fetch_from_node_cache(&parent, name)) {
@@ -597,7 +943,7 @@ impl Client {
 			parents: vec![parent.0.clone()],
 		};
 
-		let request = RestBuilder::post(&self.endpoint.metadata_url)
+		let request = try!(RestBuilder::post(&self.endpoint.metadata_url))
 			.url_push("nodes")
 			.body(try!(json::encode(&metadata)).as_bytes());
 
@@ -621,94 +967,159 @@ impl Client {
 	/// Create all directories in path if they don't exist
 	/// Returns id for the last directory in the path
 	pub fn mkdir_all<P: AsRef<Path>>(&mut self, parent: Option<&NodeId>, path: P) -> Result<NodeId> {
-		let mut current_dir = parent.unwrap_or(&self.root_id).clone();
-
-		for p in path.as_ref().components() {
-			match p {
-				Component::RootDir => current_dir = self.root_id.clone(),
-				Component::CurDir => (),
-				Component::Normal(osstr) => {
-					let name = try!(osstr.to_str().ok_or(Error::BadPath));
-					current_dir = try!(self.mkdir(Some(&current_dir), name));
-				},
-				_ => return Err(Error::BadPath),
-			}
-		}
-
-		Ok(current_dir)
+		drive::mkdir_all(self, parent, path.as_ref())
 	}
 
-	pub fn ls(&mut self, parent: &NodeId) -> Result<Vec<(String, NodeId)>> {
-		#[derive(RustcDecodable, Debug)]
-		struct NodeResponse {
-			name: String,
-			id: String,
-		}
+	/// The (available) children of folder `parent`.
+	pub fn ls(&mut self, parent: &NodeId) -> Result<Vec<Node>> {
+		self.ls_with(parent, &ListOptions::new())
+	}
 
-		#[derive(RustcDecodable, Debug)]
-		#[allow(non_snake_case)]
-		struct NodesResponse {
-			count: u64,
-			nextToken: Option<String>,
-			data: Vec<NodeResponse>,
-		}
+	/// The children of folder `parent`, filtered and sorted according to `options`.
+	pub fn ls_with(&mut self, parent: &NodeId, options: &ListOptions) -> Result<Vec<Node>> {
+		try!(self.children(parent, options)).collect()
+	}
 
-		let mut ids = Vec::new();
-		let mut next_token = None;
+	/// Like `ls_with`, but fetches the children a page at a time as the iterator is advanced,
+	/// rather than all at once.  Use `ListOptions::limit` to set the page size.
+	pub fn children(&mut self, parent: &NodeId, options: &ListOptions) -> Result<NodeIter> {
+		try!(self.connect());
 
-		loop {
-			let request = RestBuilder::get(&self.endpoint.metadata_url)
-				.url_push("nodes")
-				.url_push(&parent.0)
-				.url_push("children");
-			let request = match next_token {
-				Some(token) => request.url_query(&[("startToken", token)]),
-				None => request,
-			};
-			let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
+		let request = try!(RestBuilder::get(&self.endpoint.metadata_url))
+			.url_push("nodes")
+			.url_push(&parent.0)
+			.url_push("children");
 
-			let response: NodesResponse = match status_code {
-				StatusCode::Ok => {
-					try!(decode_server_json(&body))
-				},
-				_ => return Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
-			};
+		Ok(list::node_iter(self, request, options.query_pairs()))
+	}
 
-			for node in response.data {
-				ids.push((node.name, NodeId(node.id.clone())))
-			}
+	/// Every node matching `filter`, fetched a page at a time as the iterator is advanced.
+	/// Like Amazon, trashed nodes are only included if `filter` says something about status.
+	pub fn search(&mut self, filter: &Filter) -> Result<NodeIter> {
+		try!(self.connect());
 
-			match response.nextToken {
-				Some(token) => next_token = Some(token),
-				None => break,
-			}
-		}
+		let request = try!(RestBuilder::get(&self.endpoint.metadata_url))
+			.url_push("nodes");
 
-		Ok(ids)
+		Ok(list::node_iter(self, request, vec![("filters".to_owned(), filter.to_string())]))
 	}
 
 	pub fn download(&mut self, id: &NodeId) -> Result<Vec<u8>> {
-		let request = RestBuilder::get(&self.endpoint.content_url)
+		try!(self.connect());
+
+		let request = try!(RestBuilder::get(&self.endpoint.content_url))
 			.url_push("nodes").url_push(&id.0).url_push("content");
 		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
 
 		match status_code {
 			StatusCode::Ok => Ok(body),
+			StatusCode::NotFound => Err(Error::NodeNotFound),
 			_ => return Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
 		}
 	}
 
+	/// Download file `id` into `sink`, a chunk at a time, and return the number of bytes written.
+	/// The content is checked against the MD5 in the file's metadata once it's all been written;
+	/// if it doesn't match, `Error::DownloadMD5Mismatch` is returned (and `sink` has the bad data).
+	/// Files Amazon hasn't worked out an MD5 for yet can't be checked, so aren't.
+	/// If the connection fails part way through, the download carries on from where it got to;
+	/// errors writing to `sink` are returned at once.  Fails with `Error::NotAFile` for folders.
+	pub fn download_to(&mut self, id: &NodeId, sink: &mut Write) -> Result<u64> {
+		let node = try!(self.get_node(id));
+		if !node.is_file() {
+			return Err(Error::NotAFile);
+		}
+
+		let mut sink = DownloadSink::new(sink, 0, None);
+		try!(self.download_into(id, &mut sink));
+
+		if let Some(expected_md5) = node.md5 {
+			if sink.md5.result_str().to_lowercase() != expected_md5 {
+				return Err(Error::DownloadMD5Mismatch);
+			}
+		}
+
+		Ok(sink.written)
+	}
+
+	/// Download `length` bytes (or everything, if None) of file `id`, starting `offset` bytes in.
+	/// Less is returned if the file ends first.
+	pub fn download_range(&mut self, id: &NodeId, offset: u64, length: Option<u64>) -> Result<Vec<u8>> {
+		let mut data = Vec::new();
+		try!(self.download_range_to(id, offset, length, &mut data));
+		Ok(data)
+	}
+
+	/// Like `download_range`, but the bytes are written to `sink`, a chunk at a time.  Returns the
+	/// number of bytes written.  Unlike `download_to`, there's no MD5 to check the content against.
+	pub fn download_range_to(&mut self, id: &NodeId, offset: u64, length: Option<u64>, sink: &mut Write) -> Result<u64> {
+		if length == Some(0) {
+			return Ok(0);
+		}
+
+		try!(self.connect());
+
+		// A range that runs past any possible end just runs to the end
+		let end = length.and_then(|length| offset.checked_add(length));
+		let mut sink = DownloadSink::new(sink, offset, end);
+		try!(self.download_into(id, &mut sink));
+		Ok(sink.written)
+	}
+
+	/// Open file `id` for random access reads, without downloading all of it.
+	pub fn open(&mut self, id: &NodeId) -> Result<RemoteFile> {
+		RemoteFile::open(self, id)
+	}
+
+	/// Fetch the range of file `id` that `sink` wants.  Retries ask for whatever is still missing.
+	fn download_into(&mut self, id: &NodeId, sink: &mut DownloadSink) -> Result<()> {
+		let request = try!(RestBuilder::get(&self.endpoint.content_url))
+			.url_push("nodes").url_push(&id.0).url_push("content");
+
+		let (status_code, body) = try!(self.send_with_retry(request, true, |client, rest| {
+			// Carry on from wherever the last attempt got to
+			let from = sink.start + sink.written;
+			let rest = if from > 0 || sink.end.is_some() { rest.range(from, sink.end) } else { rest };
+
+			let response = rest.send_to(&mut *client.transport, &client.request_settings, &mut *sink);
+
+			// Neither a full disk nor a server sending the wrong range is fixed by asking again
+			if let Some(err) = sink.error.take() {
+				return Err(SendError::Fatal(err));
+			}
+
+			Ok(try!(response))
+		}));
+
+		match status_code {
+			// The body went to `sink`, even if the server ignored the Range
+			StatusCode::Ok | StatusCode::PartialContent => Ok(()),
+			// Starting at or after the end of the file
+			StatusCode::RangeNotSatisfiable => Ok(()),
+			StatusCode::NotFound => Err(Error::NodeNotFound),
+			_ => Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
+		}
+	}
+
 	/// Delete a node.
 	/// NOTE: This only sends the node to the Trash.  The user needs to manually empty their trash.
 	pub fn rm(&mut self, node: &NodeId) -> Result<()> {
-		let request = RestBuilder::put(&self.endpoint.metadata_url)
+		try!(self.connect());
+		try!(self.check_write_scope());
+
+		let request = try!(RestBuilder::put(&self.endpoint.metadata_url))
 			.url_push("trash")
 			.url_push(&node.0);
 
 		let (status_code, body) = try!(self.get_server_response_with_retry(request, true));
 
 		match status_code {
-			StatusCode::Ok => Ok(()),
+			StatusCode::Ok => {
+				// Its name is free to be used again
+				try!(self.remove_from_node_cache(node));
+				Ok(())
+			},
+			StatusCode::NotFound => Err(Error::NodeNotFound),
 			_ => Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
 		}
 	}
@@ -723,10 +1134,237 @@ fn read_json_file<T: Decodable, P: AsRef<Path>>(path: P) -> Result<T> {
 }
 
 
+/// Write `value` to `path` as JSON.
+/// The write is atomic: we write to a temporary file next to `path`, fsync it, and rename it
+/// over `path`, so a crash never leaves a truncated file behind.  The file is only readable by
+/// the owner, since these files hold our credentials.
 fn write_json_file<T: Encodable, P: AsRef<Path>>(path: P, value: &T) -> Result<()> {
-	let mut f = try!(File::create(path));
-	try!(f.write_all (&try!(json::encode(value)).into_bytes()));
-	Ok(())
+	let path = path.as_ref();
+	let data = try!(json::encode(value)).into_bytes();
+
+	let temp_path = {
+		let mut temp_name = try!(path.file_name().ok_or(Error::BadPath)).to_os_string();
+		temp_name.push(format!(".tmp{}", rand::thread_rng().gen::<u32>()));
+		path.with_file_name(temp_name)
+	};
+
+	let result: Result<()> = (|| {
+		let mut f = try!(create_private_file(&temp_path));
+		try!(f.write_all(&data));
+		try!(f.sync_all());
+		try!(fs::rename(&temp_path, path));
+
+		// Make sure the rename itself is on disk
+		if let Some(parent) = path.parent() {
+			if cfg!(unix) {
+				try!(try!(File::open(parent)).sync_all());
+			}
+		}
+
+		Ok(())
+	})();
+
+	if result.is_err() {
+		let _ = fs::remove_file(&temp_path);
+	}
+
+	result
+}
+
+
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> io::Result<File> {
+	use std::os::unix::fs::OpenOptionsExt;
+
+	fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> io::Result<File> {
+	fs::OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+
+/// Why an attempt made by `send_with_retry` failed.
+enum SendError {
+	/// Talking to the server failed; worth another try
+	Remote(Error),
+	/// Something retrying won't fix failed, like reading the content to upload or writing what
+	/// was downloaded
+	Fatal(Error),
+}
+
+impl From<Error> for SendError {
+	fn from(err: Error) -> SendError {
+		SendError::Remote(err)
+	}
+}
+
+
+/// Passes reads through, feeding everything read to an MD5 hasher.  Keeps track of how much was
+/// read, and of any error `inner` returned, so they can be told apart from network trouble.
+struct Md5Reader<'a, R: Read> {
+	inner: R,
+	md5: &'a mut Md5,
+	/// Bytes read so far
+	count: u64,
+	/// Whether `inner` has been read to the end
+	eof: bool,
+	/// The error `inner` failed with, if it did
+	error: Option<io::Error>,
+}
+
+impl<'a, R: Read> Md5Reader<'a, R> {
+	fn new(inner: R, md5: &'a mut Md5) -> Md5Reader<'a, R> {
+		Md5Reader {
+			inner: inner,
+			md5: md5,
+			count: 0,
+			eof: false,
+			error: None,
+		}
+	}
+}
+
+impl<'a, R: Read> Read for Md5Reader<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = match self.inner.read(buf) {
+			Ok(n) => n,
+			Err(err) => {
+				// Worth trying again, which io::copy does
+				if err.kind() == io::ErrorKind::Interrupted {
+					return Err(err);
+				}
+				let kind = err.kind();
+				self.error = Some(err);
+				return Err(io::Error::new(kind, "Failed to read the content to upload"));
+			},
+		};
+		self.md5.input(&buf[..n]);
+		self.count += n as u64;
+		self.eof = self.eof || (n == 0 && buf.len() > 0);
+		Ok(n)
+	}
+}
+
+
+/// Where downloads write content: passes on the wanted part of it, and hashes what it passes on.
+/// Positions are offsets into the file.  A retried request may start earlier than where the last
+/// attempt got to (or the server may ignore the Range and send the whole file), so anything
+/// outside the wanted range, or already passed on, is skipped as it streams past.
+struct DownloadSink<'a> {
+	inner: &'a mut Write,
+	md5: Md5,
+	/// Where the wanted range starts
+	start: u64,
+	/// Where the wanted range ends (exclusive), or None for the end of the file
+	end: Option<u64>,
+	/// Bytes passed on to `inner`
+	written: u64,
+	/// Position of the next byte of the current response
+	position: u64,
+	/// Why the current response was refused, if it was: `inner` failed, or it's the wrong range
+	error: Option<Error>,
+}
+
+impl<'a> DownloadSink<'a> {
+	fn new(inner: &'a mut Write, start: u64, end: Option<u64>) -> DownloadSink<'a> {
+		DownloadSink {
+			inner: inner,
+			md5: Md5::new(),
+			start: start,
+			end: end,
+			written: 0,
+			position: 0,
+			error: None,
+		}
+	}
+
+	/// Note why the current response is being refused, and make an io::Error to stop it with.
+	fn refuse(&mut self, err: Error) -> io::Error {
+		let kind = match err {
+			Error::Io(ref err) => err.kind(),
+			_ => io::ErrorKind::InvalidData,
+		};
+		self.error = Some(err);
+		io::Error::new(kind, "Download refused by its sink")
+	}
+}
+
+impl<'a> BodySink for DownloadSink<'a> {
+	fn begin(&mut self, response: &HttpResponse) -> io::Result<()> {
+		// A 200 is the whole file, whatever the Range asked for
+		let start = if response.status == 206 {
+			match response.content_range_start() {
+				Some(start) => start,
+				None => return Err(self.refuse(Error::UnknownServerError("Partial Content response without a Content-Range".to_owned()))),
+			}
+		} else {
+			0
+		};
+
+		// Anything before what's wanted is skipped, but a gap can't be filled in
+		let wanted = self.start + self.written;
+		if start > wanted {
+			return Err(self.refuse(Error::UnknownServerError(format!("Server sent a range from {}, wanted one from {}", start, wanted))));
+		}
+
+		self.position = start;
+		Ok(())
+	}
+}
+
+impl<'a> Write for DownloadSink<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let wanted = self.start + self.written;
+		let skip = cmp::min(wanted.saturating_sub(self.position), buf.len() as u64) as usize;
+		let take = match self.end {
+			Some(end) => cmp::min((buf.len() - skip) as u64, end.saturating_sub(self.position + skip as u64)) as usize,
+			None => buf.len() - skip,
+		};
+
+		let n = if take > 0 {
+			match self.inner.write(&buf[skip..skip + take]) {
+				Ok(n) => n,
+				Err(err) => {
+					if err.kind() == io::ErrorKind::Interrupted {
+						return Err(err);
+					}
+					return Err(self.refuse(Error::Io(err)));
+				},
+			}
+		} else {
+			0
+		};
+		self.md5.input(&buf[skip..skip + n]);
+		self.written += n as u64;
+
+		// Anything past the end of the range is thrown away
+		let consumed = if n < take { skip + n } else { buf.len() };
+		self.position += consumed as u64;
+		Ok(consumed)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+
+/// MD5 of `data`, as lowercase hex, the way Amazon reports it.
+fn md5_hex(data: &[u8]) -> String {
+	let mut md5 = Md5::new();
+	md5.input(data);
+	md5.result_str().to_lowercase()
+}
+
+
+/// `when` as RFC 3339, the way Amazon formats dates.  None if it's before 1970.
+fn rfc3339(when: SystemTime) -> Option<String> {
+	when.duration_since(UNIX_EPOCH).ok().map(|since_epoch| {
+		format!("{}", time::at_utc(Timespec::new(since_epoch.as_secs() as i64, since_epoch.subsec_nanos() as i32)).rfc3339())
+	})
 }
 
 
@@ -742,20 +1380,745 @@ fn decode_server_json<T: Decodable>(s: &[u8]) -> Result<T> {
 }
 
 
-fn open_webbrowser(url: &str) {
-	Command::new("xdg-open").arg(url).output().unwrap();
-}
-
-
 #[cfg(test)]
 mod test {
-	use super::{Client, read_json_file, SecurityProfile};
+	use super::{Client, ClientBuilder, NodeId, Node, UploadStatus, MTIME_PROPERTY, Transport, BodySink, HttpRequest, HttpResponse, HyperTransport, NodeKind, NodeStatus, ListOptions, Filter, AuthPrompt, read_json_file, write_json_file, SecurityProfile, Authorization, Scope, DEFAULT_SCOPES, READ_ONLY_SCOPES, JsonFileStore};
+	use super::Result as AcdResult;
+	use rustc_serialize::json;
+	use hyper::status::StatusCode;
+	use std::io::{self, Read, Write, Seek, SeekFrom, Cursor};
+	use std::sync::{Arc, Mutex, Barrier};
+	use std::thread;
 	use super::Error as AcdError;
+	use super::mock::{MockServer, Fault, MOCK_CLIENT_ID, MOCK_CLIENT_SECRET, MOCK_AUTHORIZATION_CODE};
+	use std::time::Duration;
 	use tempdir::TempDir;
 	use std::path::Path;
+	use std::fs;
 	use rand::{self, Rng};
+	use time;
+
+	/// Checks it was sent to the right login page, and hands back the mock's authorization code.
+	struct CheckingPrompt(String);
+
+	impl AuthPrompt for CheckingPrompt {
+		fn authorize(&mut self, authorization_url: &str, _redirect_uri: &str) -> AcdResult<String> {
+			assert!(authorization_url.starts_with(&(self.0.clone() + "?client_id=")));
+			Ok(MOCK_AUTHORIZATION_CODE.to_owned())
+		}
+	}
+
+	#[test]
+	fn test_custom_service_urls() {
+		let server = MockServer::start();
+
+		let mut client = ClientBuilder::new(MOCK_CLIENT_ID, MOCK_CLIENT_SECRET)
+			.auth_prompt(Box::new(CheckingPrompt(server.url("/ap/oa"))))
+			.authorize_url(&server.url("/ap/oa"))
+			.token_url(&server.url("/auth/o2/token"))
+			.endpoint_url(&server.url("/drive/v1/account/endpoint"))
+			.maximum_retry(1)
+			.lazy(true)
+			.build()
+			.unwrap();
+		assert!(server.request_log().is_empty());
+
+		assert_eq!(client.find_path(None, "/").unwrap(), Some(NodeId(server.root_id())));
+		assert_eq!(server.request_log()[..2].to_vec(), vec!["POST /auth/o2/token".to_owned(), "GET /drive/v1/account/endpoint".to_owned()]);
+	}
+
+	#[test]
+	fn test_builder_rejects_bad_urls() {
+		assert!(ClientBuilder::new("id", "secret").token_url("ftp://example.com/token").lazy(true).build().is_err());
+		assert!(ClientBuilder::new("id", "secret").endpoint_url("not a url").lazy(true).build().is_err());
+	}
+
+	#[test]
+	fn test_retry_server_errors() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(4).retry_backoff(Duration::from_millis(0)).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+
+		// 5xx, 429 and (thanks to ACD) 400 are all retried
+		server.inject_fault("GET /drive/v1/nodes", Fault::Status(StatusCode::InternalServerError));
+		server.inject_fault("GET /drive/v1/nodes", Fault::Status(StatusCode::TooManyRequests));
+		server.inject_fault("GET /drive/v1/nodes", Fault::Status(StatusCode::BadRequest));
+		assert_eq!(client.ls(&folder).unwrap().len(), 0);
+		assert_eq!(server.pending_faults(), 0);
+
+		// Until we run out of retries
+		for _ in 0..4 {
+			server.inject_fault("GET /drive/v1/nodes", Fault::Status(StatusCode::ServiceUnavailable));
+		}
+		match client.ls(&folder) {
+			Err(AcdError::ServerError(_)) => (),
+			_ => panic!("ls should give up after maximum_retry server errors"),
+		}
+		assert_eq!(server.pending_faults(), 0);
+
+		// Other errors are returned immediately
+		server.inject_fault("GET /drive/v1/nodes", Fault::Status(StatusCode::Forbidden));
+		assert!(client.ls(&folder).is_err());
+		assert_eq!(client.ls(&folder).unwrap().len(), 0);
+	}
+
+	#[test]
+	fn test_reauthorize_on_expired_token() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+		let token_requests = || server.request_log().iter().filter(|x| *x == "POST /auth/o2/token").count();
+		assert_eq!(token_requests(), 1);
+
+		// ACD sometimes says "Token has expired" in the body of a 400
+		server.inject_fault("GET /drive/v1/nodes", Fault::ExpiredToken);
+		assert_eq!(client.ls(&folder).unwrap().len(), 0);
+		assert_eq!(token_requests(), 2);
+
+		// And sometimes with a proper 401
+		server.expire_access_token();
+		assert_eq!(client.ls(&folder).unwrap().len(), 0);
+		assert_eq!(token_requests(), 3);
+	}
+
+	#[test]
+	fn test_refresh_before_expiry() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+		let token_requests = || server.request_log().iter().filter(|x| *x == "POST /auth/o2/token").count();
+		assert_eq!(token_requests(), 1);
+
+		// About to expire, so it's refreshed before the server gets a chance to reject it
+		let now = time::get_time().sec;
+		client.authorization.date_last_updated = now - 3600;
+		client.authorization.expires_at = Some(now + 10);
+		server.expire_access_token();
+		let requests_before = server.request_log().len();
+		assert_eq!(client.ls(&folder).unwrap().len(), 0);
+		assert_eq!(server.request_log()[requests_before..].to_vec(), vec!["POST /auth/o2/token".to_owned(), format!("GET /drive/v1/nodes/{}/children", folder.0)]);
+
+		// Short-lived tokens aren't refreshed on every request
+		let server = MockServer::start();
+		server.set_token_lifetime(60);
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+		for _ in 0..3 {
+			assert_eq!(client.ls(&folder).unwrap().len(), 0);
+		}
+		assert_eq!(server.request_log().iter().filter(|x| *x == "POST /auth/o2/token").count(), 1);
+	}
+
+	#[test]
+	fn test_concurrent_refresh() {
+		// Two clients (as if in two processes) sharing one token store
+		let server = Arc::new(MockServer::start());
+		let dir = TempDir::new("rust-acd-test").unwrap();
+		let folder = {
+			let mut client = server.client_builder().token_store(Box::new(JsonFileStore::new(dir.path()))).maximum_retry(1).build().unwrap();
+			client.mkdir(None, "folder").unwrap()
+		};
+		let token_requests = |server: &MockServer| server.request_log().iter().filter(|x| *x == "POST /auth/o2/token").count();
+		assert_eq!(token_requests(&server), 1);
+
+		// Both find the token has expired at once.  Only one may refresh; the other must wait for
+		// the store's lock and pick up the new tokens, since refreshing again with the old refresh
+		// token would fail.
+		let barrier = Arc::new(Barrier::new(3));
+		let threads: Vec<_> = (0..2).map(|_| {
+			let server = server.clone();
+			let barrier = barrier.clone();
+			let path = dir.path().to_path_buf();
+			let folder = folder.clone();
+
+			thread::spawn(move || {
+				let mut client = server.client_builder().token_store(Box::new(JsonFileStore::new(&path))).maximum_retry(1).build().unwrap();
+				barrier.wait();
+				barrier.wait();
+				client.ls(&folder).unwrap().len()
+			})
+		}).collect();
+
+		barrier.wait();
+		server.expire_access_token();
+		barrier.wait();
+
+		for thread in threads {
+			assert_eq!(thread.join().unwrap(), 0);
+		}
+		assert_eq!(token_requests(&server), 2);
+	}
+
+	#[test]
+	fn test_retry_truncated_and_slow_responses() {
+		let server = MockServer::start();
+		let mut client = server.client_builder()
+			.maximum_retry(2)
+			.retry_backoff(Duration::from_millis(0))
+			.read_timeout(Some(Duration::from_millis(500)))
+			.build()
+			.unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(64*1024).collect();
+		let file = client.upload(None, "file", &data, None).unwrap();
+
+		server.inject_fault("GET /cdproxy/nodes", Fault::Truncate);
+		assert_eq!(client.download(&file).unwrap(), data);
+
+		server.inject_fault("GET /cdproxy/nodes", Fault::Delay(Duration::from_secs(2)));
+		assert_eq!(client.download(&file).unwrap(), data);
+
+		assert_eq!(server.pending_faults(), 0);
+	}
+
+	#[test]
+	fn test_upload_md5_mismatch() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+
+		// The corrupt upload is trashed
+		server.inject_fault("POST /cdproxy/nodes", Fault::WrongMd5);
+		match client.upload(None, "file", b"some data", None) {
+			Err(AcdError::UnknownServerError(_)) => (),
+			_ => panic!("upload should fail when the server reports the wrong md5"),
+		}
+		let uploaded = server.nodes().into_iter().find(|node| node.name == "file").unwrap();
+		assert_eq!(uploaded.status, "TRASH");
+		assert!(client.find_path(None, "/file").unwrap().is_none());
+
+		// If it can't be trashed, the caller needs to know it's still there
+		server.inject_fault("POST /cdproxy/nodes", Fault::WrongMd5);
+		server.inject_fault("PUT /drive/v1/trash", Fault::Status(StatusCode::InternalServerError));
+		match client.upload(None, "file", b"other data", None) {
+			Err(AcdError::MD5Mismatch) => (),
+			_ => panic!("upload should report MD5Mismatch when the corrupt file couldn't be removed"),
+		}
+	}
+
+	#[test]
+	fn test_children_fetches_pages_lazily() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+		for i in 0..5 {
+			client.mkdir(Some(&folder), &format!("child{}", i)).unwrap();
+		}
+		let page_requests = || server.request_log().iter().filter(|x| x.ends_with("/children")).count();
+
+		let mut children = client.children(&folder, &ListOptions::new().limit(2)).unwrap();
+		assert_eq!(page_requests(), 0);
+		assert_eq!(children.total_count().unwrap(), 5);
+		assert_eq!(page_requests(), 1);
+
+		let first: Vec<Node> = children.by_ref().take(3).map(|node| node.unwrap()).collect();
+		assert_eq!(first.len(), 3);
+		assert_eq!(page_requests(), 2);
+
+		assert_eq!(children.count(), 2);
+		assert_eq!(page_requests(), 3);
+	}
+
+	#[test]
+	fn test_search() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+		client.upload(Some(&folder), "small", b"x", None).unwrap();
+		let big = client.upload(Some(&folder), "big", &[0; 100], None).unwrap();
+		let other = client.upload(None, "big", &[1; 100], None).unwrap();
+
+		let search = |client: &mut Client, filter: &Filter| -> Vec<NodeId> {
+			client.search(filter).unwrap().map(|node| node.unwrap().id).collect()
+		};
+
+		assert_eq!(search(&mut client, &Filter::kind(NodeKind::File).and(Filter::size(Some(10), None))), vec![big.clone(), other.clone()]);
+		assert_eq!(search(&mut client, &Filter::name("big").and(Filter::parent(&folder))), vec![big.clone()]);
+		assert_eq!(search(&mut client, &Filter::name("big").and(!Filter::parent(&folder))), vec![other.clone()]);
+		assert_eq!(search(&mut client, &Filter::size(None, Some(1)).or(Filter::kind(NodeKind::Folder).and(!Filter::is_root(true)))).len(), 2);
+
+		// Trashed nodes only show up when asked for
+		client.rm(&other).unwrap();
+		assert_eq!(search(&mut client, &Filter::name("big")), vec![big.clone()]);
+		assert_eq!(search(&mut client, &Filter::name("big").and(Filter::status(NodeStatus::Trash))), vec![other.clone()]);
+
+		// Dates go in as they are
+		let since_2000 = Filter::modified_date(Some("2000-01-01T00:00:00.000Z"), None).unwrap();
+		assert_eq!(since_2000.to_string(), "modifiedDate:[2000-01-01T00:00:00.000Z TO *]");
+		assert_eq!(search(&mut client, &Filter::name("big").and(since_2000)), vec![big.clone()]);
+		assert!(search(&mut client, &Filter::name("big").and(Filter::created_date(None, Some("2000-01-01T00:00:00.000Z")).unwrap())).is_empty());
+
+		// So anything that could break out of the range is refused
+		for hostile in &["x] OR name:big", r#"\" TO *] OR name:big OR name:"#, "*", "TO", "", "2000-01-01 00:00:00"] {
+			match Filter::created_date(Some(*hostile), None) {
+				Err(AcdError::BadFilter(_)) => (),
+				_ => panic!("{:?} should be refused", hostile),
+			}
+			assert!(Filter::modified_date(None, Some(*hostile)).is_err());
+		}
+	}
+
+	#[test]
+	fn test_find_child_with_special_characters() {
+		let server = MockServer::start();
+		let root = server.root_id();
+		let names = [
+			"hello world", "tab\tname", "a:b", "(parens)", "[brackets]", "{braces}", "quote\"d", "it's",
+			"back\\slash", "q?", "a+b-c", "x&&y||z", "!bang", "^caret~", "star*", "-", "\\",
+		];
+
+		// Decoys that a badly escaped name would match instead, e.g. "star*" as a prefix query
+		server.add_node(&root, "starry", Some(b"decoy"));
+		server.add_node(&root, "hello", Some(b"decoy"));
+		server.add_node(&root, "a", Some(b"decoy"));
+
+		let ids: Vec<String> = names.iter().map(|name| server.add_node(&root, name, Some(name.as_bytes()))).collect();
+
+		// A bad filter would be a 400, which gets retried; make that obvious
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let root = client.find_path(None, "/").unwrap().unwrap();
+
+		for (name, id) in names.iter().zip(&ids) {
+			assert_eq!(client.find_child(&root, name).unwrap(), Some(NodeId(id.clone())));
+		}
+
+		assert_eq!(client.find_child(&root, "star").unwrap(), None);
+		assert_eq!(client.search(&Filter::name("x&&y||z")).unwrap().count(), 1);
+	}
+
+	#[test]
+	fn test_upload_unless_exists() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+		let file = client.upload(Some(&folder), "file", b"some data", None).unwrap();
+		let copy = client.upload(None, "copy", b"some data", None).unwrap();
+		let uploads = || server.request_log().iter().filter(|x| x.starts_with("POST /cdproxy/nodes")).count();
+
+		let mut found: Vec<NodeId> = client.find_by_md5("1E50210A0202497FB79BC38B6ADE6C34").unwrap().into_iter().map(|node| node.id).collect();
+		found.sort_by(|a, b| a.0.cmp(&b.0));
+		assert_eq!(found, vec![file.clone(), copy.clone()]);
+		assert!(client.find_by_md5("00000000000000000000000000000000").unwrap().is_empty());
+
+		// Identical content under the same name isn't sent again
+		match client.upload_unless_exists(Some(&folder), "file", b"some data", None).unwrap() {
+			UploadStatus::AlreadyExists(node) => assert_eq!(node.id, file),
+			status => panic!("expected AlreadyExists, got {:?}", status),
+		}
+		assert_eq!(uploads(), 2);
+
+		// Identical content under another name is
+		match client.upload_unless_exists(Some(&folder), "file2", b"some data", None).unwrap() {
+			UploadStatus::Uploaded(id) => assert!(id != file && id != copy),
+			status => panic!("expected Uploaded, got {:?}", status),
+		}
+		assert_eq!(uploads(), 3);
+
+		match client.upload_unless_exists(Some(&folder), "file", b"other data", None) {
+			Err(AcdError::NodeExists) => (),
+			_ => panic!("different content under an existing name should fail with NodeExists"),
+		}
+
+		// Trashed files don't count
+		client.rm(&copy).unwrap();
+		assert_eq!(client.find_by_md5("1e50210a0202497fb79bc38b6ade6c34").unwrap().len(), 2);
+
+		// A client that couldn't have uploaded doesn't claim it did
+		let mut read_only = server.client_builder().scopes(READ_ONLY_SCOPES).maximum_retry(1).build().unwrap();
+		match read_only.upload_unless_exists(Some(&folder), "file", b"some data", None) {
+			Err(AcdError::MissingWriteScope) => (),
+			_ => panic!("upload_unless_exists should need write access"),
+		}
+	}
+
+	#[test]
+	fn test_upload_reader() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(2).retry_backoff(Duration::from_millis(0)).build().unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(1024*1024).collect();
+
+		// The source is reopened for the retry
+		let mut opened = 0;
+		server.inject_fault("POST /cdproxy/nodes", Fault::Status(StatusCode::InternalServerError));
+		let file = client.upload_reader(None, "file", Some(data.len() as u64), None, || {
+			opened += 1;
+			Ok(Cursor::new(&data[..]))
+		}).unwrap();
+		assert_eq!(opened, 2);
+		assert_eq!(client.download(&file).unwrap(), data);
+
+		// Without a length, the upload is sent chunked
+		let file = client.upload_reader(None, "file2", None, None, || Ok(&data[..])).unwrap();
+		assert_eq!(client.download(&file).unwrap(), data);
+
+		// The content is checked against what was actually read
+		server.inject_fault("POST /cdproxy/nodes", Fault::WrongMd5);
+		assert!(client.upload_reader(None, "file3", None, None, || Ok(&data[..])).is_err());
+
+		// Failing to open the source isn't retried
+		let mut opened = 0;
+		let result = client.upload_reader(None, "file4", Some(10), None, || -> io::Result<&[u8]> {
+			opened += 1;
+			Err(io::Error::new(io::ErrorKind::NotFound, "no such file"))
+		});
+		match result {
+			Err(AcdError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => (),
+			_ => panic!("upload_reader should return the error from open"),
+		}
+		assert_eq!(opened, 1);
+
+		// Neither is a source that's shorter than its length says
+		let mut opened = 0;
+		assert!(client.upload_reader(None, "file5", Some(data.len() as u64 + 1), None, || {
+			opened += 1;
+			Ok(&data[..])
+		}).is_err());
+		assert_eq!(opened, 1);
+		assert!(client.stat(None, "file5").unwrap().is_none());
+	}
+
+	/// A sink with no room left.
+	struct FullDisk;
+
+	impl Write for FullDisk {
+		fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+			Err(io::Error::new(io::ErrorKind::WriteZero, "No space left on device"))
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_download_to() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(2).retry_backoff(Duration::from_millis(0)).build().unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(256*1024).collect();
+		let file = client.upload(None, "file", &data, None).unwrap();
+
+		let mut downloaded = Vec::new();
+		assert_eq!(client.download_to(&file, &mut downloaded).unwrap(), data.len() as u64);
+		assert_eq!(downloaded, data);
+
+		// What a failed attempt already wrote isn't written twice
+		let mut downloaded = Vec::new();
+		server.inject_fault("GET /cdproxy/nodes", Fault::Truncate);
+		assert_eq!(client.download_to(&file, &mut downloaded).unwrap(), data.len() as u64);
+		assert_eq!(downloaded, data);
+
+		server.inject_fault("GET /drive/v1/nodes", Fault::WrongMd5);
+		match client.download_to(&file, &mut io::sink()) {
+			Err(AcdError::DownloadMD5Mismatch) => (),
+			_ => panic!("download_to should fail when the content doesn't match the metadata"),
+		}
+
+		// A file without an MD5 yet still downloads, unchecked
+		let mut downloaded = Vec::new();
+		server.inject_fault("GET /drive/v1/nodes", Fault::NoMd5);
+		assert_eq!(client.download_to(&file, &mut downloaded).unwrap(), data.len() as u64);
+		assert_eq!(downloaded, data);
+
+		let folder = client.mkdir(None, "folder").unwrap();
+		match client.download_to(&folder, &mut io::sink()) {
+			Err(AcdError::NotAFile) => (),
+			_ => panic!("downloading a folder should fail with NotAFile"),
+		}
+
+		// Errors writing to the sink aren't retried
+		let requests = server.request_log().len();
+		match client.download_to(&file, &mut FullDisk) {
+			Err(AcdError::Io(ref err)) if err.kind() == io::ErrorKind::WriteZero => (),
+			_ => panic!("download_to should return the sink's error"),
+		}
+		assert_eq!(server.request_log().len(), requests + 2);
+	}
+
+	#[test]
+	fn test_download_range() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(2).retry_backoff(Duration::from_millis(0)).build().unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(256*1024).collect();
+		let file = client.upload(None, "file", &data, None).unwrap();
+
+		assert_eq!(client.download_range(&file, 1000, Some(5000)).unwrap(), &data[1000..6000]);
+		assert_eq!(client.download_range(&file, 250*1024, None).unwrap(), &data[250*1024..]);
+		assert_eq!(client.download_range(&file, 250*1024, Some(1024*1024)).unwrap(), &data[250*1024..]);
+		assert_eq!(client.download_range(&file, 10, Some(::std::u64::MAX)).unwrap(), &data[10..]);
+		assert!(client.download_range(&file, data.len() as u64, None).unwrap().is_empty());
+		assert!(client.download_range(&file, 0, Some(0)).unwrap().is_empty());
+
+		// A broken transfer resumes from where it got to, rather than starting again
+		server.inject_fault("GET /cdproxy/nodes", Fault::Truncate);
+		assert_eq!(client.download_range(&file, 1000, Some(100*1024)).unwrap(), &data[1000..1000 + 100*1024]);
+
+		let ranges = Arc::new(Mutex::new(Vec::new()));
+		let transport = RangeLoggingTransport(HyperTransport::new(None, None), ranges.clone());
+		let mut client = server.client_builder().transport(Box::new(transport)).maximum_retry(2).retry_backoff(Duration::from_millis(0)).build().unwrap();
+		let mut downloaded = Vec::new();
+		server.inject_fault("GET /cdproxy/nodes", Fault::Truncate);
+		assert_eq!(client.download_to(&file, &mut downloaded).unwrap(), data.len() as u64);
+		assert_eq!(downloaded, data);
+
+		// The mock truncates to half the body
+		assert_eq!(*ranges.lock().unwrap(), vec![None, Some(format!("bytes={}-", data.len() / 2))]);
+
+		// A server that ignores the Range sends the whole file, which is streamed past rather
+		// than held in memory
+		server.inject_fault("GET /cdproxy/nodes", Fault::IgnoreRange);
+		assert_eq!(client.download_range(&file, 200*1024, Some(1000)).unwrap(), &data[200*1024..200*1024 + 1000]);
+		server.inject_fault("GET /cdproxy/nodes", Fault::Truncate);
+		server.inject_fault("GET /cdproxy/nodes", Fault::IgnoreRange);
+		let mut downloaded = Vec::new();
+		assert_eq!(client.download_to(&file, &mut downloaded).unwrap(), data.len() as u64);
+		assert_eq!(downloaded, data);
+		assert_eq!(server.pending_faults(), 0);
+	}
+
+	/// Passes requests on, noting the Range header of each content download.
+	struct RangeLoggingTransport(HyperTransport, Arc<Mutex<Vec<Option<String>>>>);
+
+	impl Transport for RangeLoggingTransport {
+		fn send(&mut self, request: &HttpRequest) -> AcdResult<HttpResponse> {
+			self.0.send(request)
+		}
+
+		fn send_to(&mut self, request: &HttpRequest, sink: &mut BodySink) -> AcdResult<HttpResponse> {
+			self.1.lock().unwrap().push(request.header("Range").map(|range| range.to_owned()));
+			let response = try!(self.0.send_to(request, sink));
+
+			// Successful bodies are streamed, never held in memory
+			if response.status >= 200 && response.status < 300 {
+				assert!(response.body.is_empty());
+			}
+			Ok(response)
+		}
+	}
+
+	/// Moves the start of each request's Range by some bytes, behind the back of `send_to`; like a
+	/// server that doesn't send the range it was asked for.
+	struct ShiftedRangeTransport(HyperTransport, i64);
+
+	impl Transport for ShiftedRangeTransport {
+		fn send(&mut self, request: &HttpRequest) -> AcdResult<HttpResponse> {
+			let mut request = request.clone();
+			for header in request.headers.iter_mut().filter(|header| header.0 == "Range") {
+				let range = header.1["bytes=".len()..].to_owned();
+				let dash = range.find('-').unwrap();
+				let start: i64 = range[..dash].parse().unwrap();
+				header.1 = format!("bytes={}{}", start + self.1, &range[dash..]);
+			}
+			self.0.send(&request)
+		}
+	}
+
+	#[test]
+	fn test_download_range_content_range() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().build().unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(16*1024).collect();
+		let file = client.upload(None, "file", &data, None).unwrap();
+
+		// A range starting early is fine; the extra bytes are skipped
+		let transport = ShiftedRangeTransport(HyperTransport::new(None, None), -100);
+		let mut client = server.client_builder().transport(Box::new(transport)).maximum_retry(1).build().unwrap();
+		assert_eq!(client.download_range(&file, 1000, Some(500)).unwrap(), &data[1000..1500]);
+
+		// One starting late would leave a gap
+		let transport = ShiftedRangeTransport(HyperTransport::new(None, None), 100);
+		let mut client = server.client_builder().transport(Box::new(transport)).maximum_retry(1).build().unwrap();
+		match client.download_range(&file, 1000, Some(500)) {
+			Err(AcdError::UnknownServerError(_)) => (),
+			_ => panic!("a range starting after the one asked for should fail"),
+		}
+	}
+
+	#[test]
+	fn test_remote_file() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(100*1024).collect();
+		let file = client.upload(None, "file", &data, None).unwrap();
+		let content_requests = || server.request_log().iter().filter(|x| x.starts_with("GET /cdproxy")).count();
+
+		let mut remote = client.open(&file).unwrap();
+		remote.set_read_ahead(16*1024);
+		assert_eq!(remote.len(), data.len() as u64);
+		assert!(!remote.is_empty());
+
+		// The tail, like an archive's index
+		let mut tail = Vec::new();
+		assert_eq!(remote.seek(SeekFrom::End(-100)).unwrap(), data.len() as u64 - 100);
+		remote.read_to_end(&mut tail).unwrap();
+		assert_eq!(tail, &data[data.len() - 100..]);
+		assert_eq!(content_requests(), 1);
+
+		// Small reads are served from the read-ahead
+		let mut buf = [0; 10];
+		remote.seek(SeekFrom::Start(1000)).unwrap();
+		remote.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf[..], &data[1000..1010]);
+		remote.seek(SeekFrom::Current(5000)).unwrap();
+		remote.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf[..], &data[6010..6020]);
+		assert_eq!(content_requests(), 2);
+
+		// Reads bigger than the read-ahead still work
+		let mut all = Vec::new();
+		remote.seek(SeekFrom::Start(0)).unwrap();
+		remote.read_to_end(&mut all).unwrap();
+		assert_eq!(all, data);
+
+		assert!(remote.seek(SeekFrom::Current(-1_000_000)).is_err());
+		remote.seek(SeekFrom::End(10)).unwrap();
+		assert_eq!(remote.read(&mut buf).unwrap(), 0);
+	}
+
+	#[test]
+	fn test_remote_file_changed() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(100*1024).collect();
+		let file = client.upload(None, "file", &data, None).unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+
+		match client.open(&folder) {
+			Err(AcdError::NotAFile) => (),
+			_ => panic!("opening a folder should fail with NotAFile"),
+		}
+
+		let metadata_requests = || server.request_log().iter().filter(|x| x.starts_with("GET /drive/v1/nodes")).count();
+		let mut remote = client.open(&file).unwrap();
+		remote.set_read_ahead(16*1024);
+		let mut buf = [0u8; 10];
+
+		// Unless asked to, fetches don't check the version
+		let before = metadata_requests();
+		remote.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf[..], &data[..10]);
+		assert_eq!(metadata_requests(), before);
+
+		// Overwritten while open, so the rest would come from different content
+		remote.set_check_version(true);
+		server.overwrite(&file.0, &data[..50*1024]);
+		remote.seek(SeekFrom::Start(20*1024)).unwrap();
+		let err = remote.read(&mut buf).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::Other);
+		match err.get_ref().and_then(|err| err.downcast_ref::<AcdError>()) {
+			Some(&AcdError::NodeChanged) => (),
+			_ => panic!("reading a file changed since it was opened should fail with NodeChanged"),
+		}
+	}
+
+	#[test]
+	fn test_upload_file() {
+		let server = MockServer::start();
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+		let temp_dir = TempDir::new("rust-acd-test").unwrap();
+		let data: Vec<u8> = rand::thread_rng().gen_iter().take(64*1024).collect();
+
+		let photo_path = temp_dir.path().join("photo.JPG");
+		fs::File::create(&photo_path).unwrap().write_all(&data).unwrap();
+		let photo = client.upload_file(None, &photo_path, "photo.JPG", true).unwrap();
+		assert_eq!(client.download(&photo).unwrap(), data);
+
+		let node = client.get_node(&photo).unwrap();
+		assert_eq!(node.content_type, Some("image/jpeg".to_owned()));
+		let mtime = super::rfc3339(fs::metadata(&photo_path).unwrap().modified().unwrap()).unwrap();
+		assert_eq!(node.properties[MOCK_CLIENT_ID][MTIME_PROPERTY], mtime);
+
+		// Unknown extensions (and plain upload) get the generic type
+		let other_path = temp_dir.path().join("data.unknown");
+		fs::File::create(&other_path).unwrap().write_all(b"some data").unwrap();
+		let other = client.upload_file(None, &other_path, "data", false).unwrap();
+		let node = client.get_node(&other).unwrap();
+		assert_eq!(node.content_type, Some("application/octet-stream".to_owned()));
+		assert!(node.properties.is_empty());
+
+		let plain = client.upload(None, "plain", b"other data", None).unwrap();
+		assert_eq!(server.node(&plain.0).unwrap().content_type, Some("application/octet-stream".to_owned()));
+
+		match client.upload_file(None, temp_dir.path(), "dir", false) {
+			Err(AcdError::BadPath) => (),
+			_ => panic!("uploading a directory should fail with BadPath"),
+		}
+	}
+
+	#[test]
+	fn test_write_json_file() {
+		let temp_dir = TempDir::new("rust-acd-test").unwrap();
+		let path = temp_dir.path().join("security_profile.json");
+		let security_profile = SecurityProfile {
+			client_id: "id".to_owned(),
+			client_secret: "secret".to_owned(),
+		};
+
+		write_json_file(&path, &security_profile).unwrap();
+		write_json_file(&path, &security_profile).unwrap();
+
+		let read_back: SecurityProfile = read_json_file(&path).unwrap();
+		assert_eq!(read_back.client_secret, "secret");
+
+		// No temporary files left lying around
+		assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+		assert_owner_only(&path);
+	}
+
+	#[test]
+	fn test_authorization_scopes() {
+		// Authorizations saved before scopes were tracked were granted read_all and write
+		let legacy: Authorization = json::decode(r#"{"access_token":"a","refresh_token":"r","token_type":"bearer","date_last_updated":0}"#).unwrap();
+		assert!(legacy.has_scope(Scope::Write));
+		assert!(legacy.has_exactly_scopes(DEFAULT_SCOPES));
+		assert!(!legacy.has_exactly_scopes(READ_ONLY_SCOPES));
+
+		let read_only = Authorization {
+			scopes: Some(vec!["clouddrive:read_all".to_owned()]),
+			..legacy
+		};
+		assert!(read_only.has_scope(Scope::ReadAll));
+		assert!(!read_only.has_scope(Scope::Write));
+		assert!(read_only.has_exactly_scopes(READ_ONLY_SCOPES));
+		assert!(!read_only.has_exactly_scopes(DEFAULT_SCOPES));
+	}
+
+	#[test]
+	fn test_read_only_client_ignores_broader_grant() {
+		let server = MockServer::start();
+		let config_dir = TempDir::new("rust-acd-test").unwrap();
+		let token_requests = || server.request_log().iter().filter(|x| *x == "POST /auth/o2/token").count();
+
+		// A read+write authorization is already in the store
+		server.client_builder().config_dir(config_dir.path()).build().unwrap();
+		assert_eq!(token_requests(), 1);
+
+		// A read-only client asks for its own, rather than using that one
+		let mut client = server.client_builder().config_dir(config_dir.path()).scopes(READ_ONLY_SCOPES).build().unwrap();
+		assert_eq!(token_requests(), 2);
+		assert_eq!(client.authorization.scopes, Some(vec!["clouddrive:read_all".to_owned()]));
+		match client.mkdir(None, "folder") {
+			Err(AcdError::MissingWriteScope) => (),
+			_ => panic!("a read-only client should not be able to write"),
+		}
+
+		// Same for authorizations saved before scopes were tracked, which were read+write
+		let legacy = Authorization {
+			scopes: None,
+			..client.authorization.clone()
+		};
+		write_json_file(config_dir.path().join(".acd").join("authorization.json"), &legacy).unwrap();
+		server.client_builder().config_dir(config_dir.path()).scopes(READ_ONLY_SCOPES).build().unwrap();
+		assert_eq!(token_requests(), 3);
+	}
+
+	#[cfg(unix)]
+	fn assert_owner_only(path: &Path) {
+		use std::os::unix::fs::PermissionsExt;
+		assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+	}
+
+	#[cfg(not(unix))]
+	fn assert_owner_only(_path: &Path) {}
 
-	// TODO: Test concurrent instances to make sure they don't stomp eachother's config_dir.
 	#[test]
 	fn test_everything() {
 		let security_profile: SecurityProfile = read_json_file("test.security_profile.json").unwrap();
@@ -791,7 +2154,7 @@ mod test {
 		assert!(client.find_path(Some(&temp_upload_dir), Path::new("thisdoesntexist")).unwrap().is_none());
 
 		// Test ls
-		let ls_result = client.ls(&mkdir_test_dir).unwrap();
+		let ls_result: Vec<(String, NodeId)> = client.ls(&mkdir_test_dir).unwrap().into_iter().map(|node| (node.name.unwrap(), node.id)).collect();
 		assert_eq!(ls_result.len(), 2);
 		assert!(
 		         (ls_result[0].0 == "small_data" && ls_result[0].1 == small_data_node && ls_result[1].0 == "large_data" && ls_result[1].1 == large_data_node)
@@ -0,0 +1,293 @@
+use std::cmp::Ordering;
+use std::vec;
+use rustc_serialize::json;
+use hyper::status::StatusCode;
+use error::{Result, Error};
+use node::{Node, NodeKind, NodeStatus, NodeJson};
+use rest::RestBuilder;
+use filter::Filter;
+use super::{Client, decode_server_json};
+
+
+/// Node properties that listings can be sorted by.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortField {
+	Name,
+	Kind,
+	CreatedDate,
+	ModifiedDate,
+	Size,
+}
+
+impl SortField {
+	/// As the API spells it
+	pub fn as_str(&self) -> &'static str {
+		match *self {
+			SortField::Name => "name",
+			SortField::Kind => "kind",
+			SortField::CreatedDate => "createdDate",
+			SortField::ModifiedDate => "modifiedDate",
+			SortField::Size => "contentProperties.size",
+		}
+	}
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortOrder {
+	Ascending,
+	Descending,
+}
+
+impl SortOrder {
+	/// As the API spells it
+	pub fn as_str(&self) -> &'static str {
+		match *self {
+			SortOrder::Ascending => "ASC",
+			SortOrder::Descending => "DESC",
+		}
+	}
+}
+
+
+/// Which children `Client::ls_with` returns, and in what order.
+///
+/// ```
+/// use acd::{ListOptions, NodeKind, SortField, SortOrder};
+///
+/// // Just the folders, biggest first
+/// let options = ListOptions::new()
+///     .kind(NodeKind::Folder)
+///     .sort(SortField::Size, SortOrder::Descending);
+/// ```
+#[derive(Clone, Default, Debug)]
+pub struct ListOptions {
+	kinds: Vec<NodeKind>,
+	statuses: Vec<NodeStatus>,
+	sort: Vec<(SortField, SortOrder)>,
+	limit: Option<u32>,
+}
+
+impl ListOptions {
+	/// Everything, in whatever order the server likes.
+	pub fn new() -> ListOptions {
+		ListOptions::default()
+	}
+
+	/// Only list nodes of kind `kind`.  Can be given more than once, to list several kinds.
+	pub fn kind(mut self, kind: NodeKind) -> ListOptions {
+		self.kinds.push(kind);
+		self
+	}
+
+	/// Only list nodes with status `status`.  Can be given more than once, to list several.
+	/// Without a status, the server lists available nodes only.
+	pub fn status(mut self, status: NodeStatus) -> ListOptions {
+		self.statuses.push(status);
+		self
+	}
+
+	/// Sort by `field`.  Given more than once, later fields break ties in earlier ones.
+	pub fn sort(mut self, field: SortField, order: SortOrder) -> ListOptions {
+		self.sort.push((field, order));
+		self
+	}
+
+	/// Fetch `limit` nodes per request, rather than the server's default.  Amazon allows at most 200.
+	pub fn limit(mut self, limit: u32) -> ListOptions {
+		self.limit = Some(limit);
+		self
+	}
+
+	/// The `filters` query parameter, if any.
+	fn filter(&self) -> Option<Filter> {
+		let kinds = any_of(self.kinds.iter().map(|kind| Filter::kind(kind.clone())));
+		let statuses = any_of(self.statuses.iter().map(|status| Filter::status(status.clone())));
+
+		match (kinds, statuses) {
+			(Some(kinds), Some(statuses)) => Some(kinds.and(statuses)),
+			(kinds, statuses) => kinds.or(statuses),
+		}
+	}
+
+	/// The `sort` query parameter, if any; a JSON list like ["name ASC"].
+	fn sort_param(&self) -> Option<String> {
+		if self.sort.is_empty() {
+			return None;
+		}
+
+		let sort: Vec<String> = self.sort.iter().map(|&(field, order)| format!("{} {}", field.as_str(), order.as_str())).collect();
+		Some(json::encode(&sort).unwrap())
+	}
+
+	/// The query parameters these options add to a listing request.
+	pub fn query_pairs(&self) -> Vec<(String, String)> {
+		let mut pairs = Vec::new();
+
+		if let Some(filter) = self.filter() {
+			pairs.push(("filters".to_owned(), filter.to_string()));
+		}
+
+		if let Some(sort) = self.sort_param() {
+			pairs.push(("sort".to_owned(), sort));
+		}
+
+		if let Some(limit) = self.limit {
+			pairs.push(("limit".to_owned(), limit.to_string()));
+		}
+
+		pairs
+	}
+}
+
+
+/// A listing that fetches its nodes from the server a page at a time, as they're needed.
+/// Returned by `Client::children` and `Client::search`.
+///
+/// Iteration stops after the first error.
+pub struct NodeIter<'a> {
+	client: &'a mut Client,
+	/// The listing request, without paging parameters
+	request: RestBuilder,
+	query: Vec<(String, String)>,
+	/// What's left of the current page
+	page: vec::IntoIter<Node>,
+	/// None once the last page has been fetched (or before the first one has)
+	next_token: Option<String>,
+	/// Set by the first page
+	count: Option<u64>,
+	finished: bool,
+}
+
+/// A `NodeIter` over the listing `request`, with `query` added to every page's request.
+/// A function rather than `NodeIter::new`, since `RestBuilder` is private to the crate.
+pub fn node_iter(client: &mut Client, request: RestBuilder, query: Vec<(String, String)>) -> NodeIter {
+	NodeIter {
+		client: client,
+		request: request,
+		query: query,
+		page: Vec::new().into_iter(),
+		next_token: None,
+		count: None,
+		finished: false,
+	}
+}
+
+impl<'a> NodeIter<'a> {
+	/// How many nodes the server says are in the whole listing.  Fetches the first page if it
+	/// hasn't been already.
+	pub fn total_count(&mut self) -> Result<u64> {
+		if self.count.is_none() {
+			try!(self.fetch_page());
+		}
+
+		Ok(self.count.unwrap_or(0))
+	}
+
+	fn fetch_page(&mut self) -> Result<()> {
+		#[derive(RustcDecodable, Debug)]
+		#[allow(non_snake_case)]
+		struct NodesResponse {
+			count: u64,
+			nextToken: Option<String>,
+			data: Vec<NodeJson>,
+		}
+
+		let mut query = self.query.clone();
+		if let Some(token) = self.next_token.take() {
+			query.push(("startToken".to_owned(), token));
+		}
+
+		let request = if query.is_empty() {
+			self.request.clone()
+		} else {
+			self.request.clone().url_query(&query)
+		};
+		let (status_code, body) = try!(self.client.get_server_response_with_retry(request, true));
+
+		let response: NodesResponse = match status_code {
+			StatusCode::Ok => {
+				try!(decode_server_json(&body))
+			},
+			StatusCode::NotFound => return Err(Error::NodeNotFound),
+			_ => return Err(Error::UnknownServerError(format!("Unknown Server Response, probably an error. Status was {}, Body was {:?}", status_code, String::from_utf8(body)))),
+		};
+
+		// A missing nextToken, or an empty page, is the end of the listing
+		self.finished = response.nextToken.is_none() || response.data.is_empty();
+		self.next_token = response.nextToken;
+		self.count = Some(response.count);
+		self.page = response.data.into_iter().map(|node| node.into_node()).collect::<Vec<_>>().into_iter();
+		Ok(())
+	}
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+	type Item = Result<Node>;
+
+	fn next(&mut self) -> Option<Result<Node>> {
+		loop {
+			if let Some(node) = self.page.next() {
+				return Some(Ok(node));
+			}
+
+			if self.finished {
+				return None;
+			}
+
+			if let Err(err) = self.fetch_page() {
+				self.finished = true;
+				return Some(Err(err));
+			}
+		}
+	}
+}
+
+
+/// `filters` ORed together, or None if there aren't any.
+fn any_of<I: Iterator<Item=Filter>>(filters: I) -> Option<Filter> {
+	filters.fold(None, |any, filter| match any {
+		Some(any) => Some(any.or(filter)),
+		None => Some(filter),
+	})
+}
+
+
+/// Do what the server would do with `options`, for `CloudDrive`s that aren't a server.
+/// `nodes` is every child, including trashed ones.
+pub fn apply_locally(options: &ListOptions, nodes: Vec<Node>) -> Vec<Node> {
+	let mut nodes: Vec<Node> = nodes.into_iter().filter(|node| {
+		let kind_ok = options.kinds.is_empty() || options.kinds.contains(&node.kind);
+		let status_ok = if options.statuses.is_empty() {
+			node.status == NodeStatus::Available
+		} else {
+			options.statuses.contains(&node.status)
+		};
+
+		kind_ok && status_ok
+	}).collect();
+
+	nodes.sort_by(|a, b| {
+		for &(field, order) in &options.sort {
+			let ordering = match field {
+				SortField::Name => a.name.cmp(&b.name),
+				SortField::Kind => a.kind.as_str().cmp(b.kind.as_str()),
+				SortField::CreatedDate => a.created_date.cmp(&b.created_date),
+				SortField::ModifiedDate => a.modified_date.cmp(&b.modified_date),
+				SortField::Size => a.size.cmp(&b.size),
+			};
+			let ordering = match order {
+				SortOrder::Ascending => ordering,
+				SortOrder::Descending => ordering.reverse(),
+			};
+
+			if ordering != Ordering::Equal {
+				return ordering;
+			}
+		}
+
+		Ordering::Equal
+	});
+
+	nodes
+}
@@ -0,0 +1,1467 @@
+//! An in-process stand-in for Amazon Cloud Drive, for testing code that uses this crate without
+//! touching the network or needing a real account.
+//!
+//! `MockServer` serves the subset of the Cloud Drive REST API that `Client` uses, backed by an
+//! in-memory node tree, over plain http on localhost.  `MockServer::client_builder` returns a
+//! `ClientBuilder` already pointed at the server and set up to authorize against it.
+//!
+//! ```no_run
+//! use acd::mock::MockServer;
+//!
+//! let server = MockServer::start();
+//! let mut client = server.client_builder().build().unwrap();
+//! let folder = client.mkdir(None, "backups").unwrap();
+//! client.upload(Some(&folder), "hello.txt", b"Hello, world!", None).unwrap();
+//! ```
+//!
+//! Only enabled with the `mock` cargo feature.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, BTreeMap};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicBool};
+use std::str;
+use std::thread;
+use std::time::Duration;
+use hyper::server::{Handler, Request, Response};
+use hyper::net::{Fresh, HttpStream, NetworkStream};
+use hyper::uri::RequestUri;
+use hyper::method::Method;
+use hyper::header::{self, Headers};
+use hyper::status::StatusCode;
+use rustc_serialize::json;
+use url::form_urlencoded;
+use crypto::md5::Md5;
+use crypto::digest::Digest;
+use time;
+use auth::AuthPrompt;
+use builder::ClientBuilder;
+use error::Result;
+
+
+/// The client_id `MockServer::client_builder` uses.
+pub const MOCK_CLIENT_ID: &'static str = "amzn1.application-oa2-client.mock";
+/// The client_secret `MockServer::client_builder` uses.
+pub const MOCK_CLIENT_SECRET: &'static str = "mock_client_secret";
+/// The only authorization code the mock token endpoint accepts.
+pub const MOCK_AUTHORIZATION_CODE: &'static str = "mock_authorization_code";
+/// How long (in seconds) the mock's access tokens claim to be valid for, unless told otherwise.
+pub const MOCK_TOKEN_LIFETIME: u64 = 3600;
+/// Page size for node listings when the request doesn't specify a limit.
+const DEFAULT_PAGE_SIZE: usize = 200;
+
+
+/// An `AuthPrompt` that "logs in" to the mock by immediately returning `MOCK_AUTHORIZATION_CODE`.
+pub struct MockAuthPrompt;
+
+impl AuthPrompt for MockAuthPrompt {
+	fn authorize(&mut self, _authorization_url: &str, _redirect_uri: &str) -> Result<String> {
+		Ok(MOCK_AUTHORIZATION_CODE.to_owned())
+	}
+}
+
+
+/// A node in the mock's tree, as the mock's tests see it.
+#[derive(Clone, Debug)]
+pub struct MockNode {
+	pub id: String,
+	pub name: String,
+	pub kind: String,
+	pub parents: Vec<String>,
+	pub status: String,
+	pub is_root: bool,
+	pub content: Vec<u8>,
+	pub content_type: Option<String>,
+	pub labels: Vec<String>,
+	pub description: Option<String>,
+	pub created_date: String,
+	pub modified_date: String,
+	pub version: u64,
+	/// By owner, then key
+	pub properties: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl MockNode {
+	fn md5(&self) -> Option<String> {
+		if self.kind != "FILE" {
+			return None;
+		}
+
+		let mut md5 = Md5::new();
+		md5.input(&self.content);
+		Some(md5.result_str().to_lowercase())
+	}
+
+	fn to_json(&self) -> NodeJson {
+		NodeJson {
+			id: self.id.clone(),
+			// Amazon's root folder has no name
+			name: if self.is_root { None } else { Some(self.name.clone()) },
+			kind: self.kind.clone(),
+			parents: self.parents.clone(),
+			status: self.status.clone(),
+			isRoot: self.is_root,
+			labels: self.labels.clone(),
+			description: self.description.clone(),
+			createdDate: self.created_date.clone(),
+			modifiedDate: self.modified_date.clone(),
+			version: self.version,
+			properties: self.properties.clone(),
+			contentProperties: self.md5().map(|md5| ContentPropertiesJson {
+				size: self.content.len() as u64,
+				md5: md5,
+				contentType: self.content_type.clone().unwrap_or("application/octet-stream".to_owned()),
+			}),
+		}
+	}
+}
+
+
+#[derive(RustcEncodable)]
+#[allow(non_snake_case)]
+struct NodeJson {
+	id: String,
+	name: Option<String>,
+	kind: String,
+	parents: Vec<String>,
+	status: String,
+	isRoot: bool,
+	labels: Vec<String>,
+	description: Option<String>,
+	createdDate: String,
+	modifiedDate: String,
+	version: u64,
+	properties: BTreeMap<String, BTreeMap<String, String>>,
+	contentProperties: Option<ContentPropertiesJson>,
+}
+
+#[derive(RustcEncodable)]
+#[allow(non_snake_case)]
+struct ContentPropertiesJson {
+	size: u64,
+	md5: String,
+	contentType: String,
+}
+
+#[derive(RustcEncodable)]
+#[allow(non_snake_case)]
+struct NodeListJson {
+	count: u64,
+	nextToken: Option<String>,
+	data: Vec<NodeJson>,
+}
+
+#[derive(RustcDecodable)]
+struct PropertyRequest {
+	value: String,
+}
+
+#[derive(RustcDecodable)]
+struct CreateNodeRequest {
+	name: String,
+	kind: String,
+	parents: Option<Vec<String>>,
+	labels: Option<Vec<String>>,
+	description: Option<String>,
+}
+
+
+/// Something for the mock to do wrong, to exercise a client's error handling.
+/// See `MockServer::inject_fault`.
+#[derive(Clone, Debug)]
+pub enum Fault {
+	/// Respond with this status (and a JSON error message) instead of handling the request
+	Status(StatusCode),
+	/// Respond 400 with a "Token has expired" message, the way ACD sometimes does instead of a 401
+	ExpiredToken,
+	/// Handle the request, but close the connection halfway through the response body
+	Truncate,
+	/// Wait this long before handling the request
+	Delay(Duration),
+	/// Handle the request, but report the wrong md5 in the node's contentProperties
+	WrongMd5,
+	/// Handle the request, but leave the md5 out of the node's contentProperties, as ACD does
+	/// for files it hasn't finished processing
+	NoMd5,
+	/// Handle the request as if it had no Range header
+	IgnoreRange,
+}
+
+
+/// What the mock sends back for a request.
+struct MockResponse {
+	status: StatusCode,
+	headers: Headers,
+	body: Vec<u8>,
+}
+
+impl MockResponse {
+	fn json(status: StatusCode, body: String) -> MockResponse {
+		let mut headers = Headers::new();
+		headers.set(header::ContentType(mime!(Application/Json)));
+
+		MockResponse {
+			status: status,
+			headers: headers,
+			body: body.into_bytes(),
+		}
+	}
+
+	fn message(status: StatusCode, message: &str) -> MockResponse {
+		MockResponse::json(status, format!("{{\"message\":{}}}", json::Json::String(message.to_owned())))
+	}
+
+	fn node(status: StatusCode, node: &MockNode) -> MockResponse {
+		MockResponse::json(status, json::encode(&node.to_json()).unwrap())
+	}
+
+	/// Replace contentProperties.md5 in a node response with `md5`, or remove it if None.
+	fn with_md5(mut self, md5: Option<&str>) -> MockResponse {
+		let mut node = match str::from_utf8(&self.body).ok().and_then(|body| json::Json::from_str(body).ok()) {
+			Some(node) => node,
+			None => return self,
+		};
+
+		if let Some(properties) = node.as_object_mut().and_then(|node| node.get_mut("contentProperties")).and_then(|properties| properties.as_object_mut()) {
+			match md5 {
+				Some(md5) => properties.insert("md5".to_owned(), json::Json::String(md5.to_owned())),
+				None => properties.remove("md5"),
+			};
+		}
+
+		self.body = node.to_string().into_bytes();
+		self
+	}
+
+	fn conflict(node_id: &str) -> MockResponse {
+		MockResponse::json(StatusCode::Conflict, format!("{{\"code\":\"NAME_ALREADY_EXISTS\",\"message\":\"Node with the name already exists under parentId\",\"info\":{{\"nodeId\":{}}}}}", json::Json::String(node_id.to_owned())))
+	}
+}
+
+
+/// The request, boiled down to what the mock cares about.
+struct MockRequest {
+	method: Method,
+	/// Path segments, with empty segments removed (our Client produces "//" in places).
+	path: Vec<String>,
+	query: Vec<(String, String)>,
+	headers: Headers,
+	body: Vec<u8>,
+}
+
+impl MockRequest {
+	fn query_param(&self, name: &str) -> Option<&str> {
+		self.query.iter().find(|&x| x.0 == name).map(|x| &x.1[..])
+	}
+
+	fn header(&self, name: &str) -> Option<String> {
+		self.headers.get_raw(name).and_then(|values| values.first()).and_then(|value| String::from_utf8(value.clone()).ok())
+	}
+
+	fn bearer_token(&self) -> Option<String> {
+		self.headers.get::<header::Authorization<header::Bearer>>().map(|auth| auth.0.token.clone())
+	}
+
+	fn host(&self) -> String {
+		match self.headers.get::<header::Host>() {
+			Some(host) => format!("{}:{}", host.hostname, host.port.unwrap_or(80)),
+			None => "localhost".to_owned(),
+		}
+	}
+}
+
+
+/// If `request` is a `method` request for a path matching `pattern`, return the path segment
+/// matched by "*" (or an empty string if the pattern has no "*").
+fn route(request: &MockRequest, method: Method, pattern: &[&str]) -> Option<String> {
+	route_all(request, method, pattern).map(|mut captured| captured.pop().unwrap_or_default())
+}
+
+
+/// Like `route`, but returns the segments matched by every "*", in order.
+fn route_all(request: &MockRequest, method: Method, pattern: &[&str]) -> Option<Vec<String>> {
+	if request.method != method || request.path.len() != pattern.len() {
+		return None;
+	}
+
+	let mut captured = Vec::new();
+
+	for (segment, expected) in request.path.iter().zip(pattern) {
+		if *expected == "*" {
+			captured.push(segment.clone());
+		} else if segment != expected {
+			return None;
+		}
+	}
+
+	Some(captured)
+}
+
+
+/// Parse a Range header (a single range of bytes) for content `length` bytes long, into the start
+/// and (exclusive) end of the range.  None if the range isn't valid or doesn't overlap the content.
+fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+	if !range.starts_with("bytes=") {
+		return None;
+	}
+
+	let spec = &range["bytes=".len()..];
+	let dash = match spec.find('-') {
+		Some(dash) => dash,
+		None => return None,
+	};
+	let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+	let (start, end) = if first.is_empty() {
+		// The last `last` bytes
+		match last.parse::<u64>() {
+			Ok(suffix) if suffix > 0 => (length.saturating_sub(suffix), length),
+			_ => return None,
+		}
+	} else {
+		let start = match first.parse::<u64>() {
+			Ok(start) => start,
+			Err(_) => return None,
+		};
+		let end = if last.is_empty() {
+			length
+		} else {
+			match last.parse::<u64>() {
+				Ok(last) if last >= start => ::std::cmp::min(last + 1, length),
+				_ => return None,
+			}
+		};
+		(start, end)
+	};
+
+	if start < end {
+		Some((start, end))
+	} else {
+		None
+	}
+}
+
+
+/// Parse the `sort` parameter, a JSON list like ["name ASC", "createdDate DESC"], into
+/// (field, descending) pairs.  None if it isn't valid.
+fn parse_sort(sort: &str) -> Option<Vec<(String, bool)>> {
+	let sort: Vec<String> = match json::decode(sort) {
+		Ok(sort) => sort,
+		Err(_) => return None,
+	};
+	let mut keys = Vec::new();
+
+	for key in sort {
+		let mut words = key.split(' ').filter(|word| !word.is_empty());
+		let field = match words.next() {
+			Some(field @ "name") | Some(field @ "kind") | Some(field @ "createdDate") | Some(field @ "modifiedDate") | Some(field @ "contentProperties.size") => field.to_owned(),
+			_ => return None,
+		};
+		let descending = match words.next() {
+			None | Some("ASC") => false,
+			Some("DESC") => true,
+			_ => return None,
+		};
+
+		if words.next().is_some() {
+			return None;
+		}
+		keys.push((field, descending));
+	}
+
+	Some(keys)
+}
+
+
+/// Order `a` and `b` by sort `keys`, as returned by `parse_sort`.
+fn compare_nodes(a: &MockNode, b: &MockNode, keys: &[(String, bool)]) -> Ordering {
+	for &(ref field, descending) in keys {
+		let ordering = match &field[..] {
+			"name" => a.name.cmp(&b.name),
+			"kind" => a.kind.cmp(&b.kind),
+			"createdDate" => a.created_date.cmp(&b.created_date),
+			"modifiedDate" => a.modified_date.cmp(&b.modified_date),
+			_ => a.md5().map(|_| a.content.len()).cmp(&b.md5().map(|_| b.content.len())),
+		};
+		let ordering = if descending { ordering.reverse() } else { ordering };
+
+		if ordering != Ordering::Equal {
+			return ordering;
+		}
+	}
+
+	Ordering::Equal
+}
+
+
+struct MockState {
+	nodes: HashMap<String, MockNode>,
+	/// Insertion order of nodes, so listings are stable
+	order: Vec<String>,
+	root_id: String,
+	next_id: u64,
+	access_token: Option<String>,
+	refresh_token: Option<String>,
+	next_token: u64,
+	/// expires_in for the tokens we hand out
+	token_lifetime: u64,
+	page_size: usize,
+	request_log: Vec<String>,
+	/// Injected faults, in order, with the request prefix each applies to
+	faults: Vec<(String, Fault)>,
+}
+
+impl MockState {
+	fn new() -> MockState {
+		let mut state = MockState {
+			nodes: HashMap::new(),
+			order: Vec::new(),
+			root_id: String::new(),
+			next_id: 0,
+			access_token: None,
+			refresh_token: None,
+			next_token: 0,
+			token_lifetime: MOCK_TOKEN_LIFETIME,
+			page_size: DEFAULT_PAGE_SIZE,
+			request_log: Vec::new(),
+			faults: Vec::new(),
+		};
+
+		let root = state.new_node("", "FOLDER", Vec::new());
+		state.nodes.get_mut(&root).unwrap().is_root = true;
+		state.root_id = root;
+		state
+	}
+
+	fn new_node(&mut self, name: &str, kind: &str, parents: Vec<String>) -> String {
+		self.next_id += 1;
+		let id = format!("mockNode{:014}", self.next_id);
+		let now = format!("{}", time::now_utc().rfc3339());
+
+		self.nodes.insert(id.clone(), MockNode {
+			id: id.clone(),
+			name: name.to_owned(),
+			kind: kind.to_owned(),
+			parents: parents,
+			status: "AVAILABLE".to_owned(),
+			is_root: false,
+			content: Vec::new(),
+			content_type: None,
+			labels: Vec::new(),
+			description: None,
+			created_date: now.clone(),
+			modified_date: now,
+			version: 1,
+			properties: BTreeMap::new(),
+		});
+		self.order.push(id.clone());
+		id
+	}
+
+	/// The available child of `parent` called `name`, if any.
+	fn find_child(&self, parent: &str, name: &str) -> Option<&MockNode> {
+		self.order.iter()
+			.map(|id| &self.nodes[id])
+			.find(|node| node.status == "AVAILABLE" && node.name == name && node.parents.iter().any(|p| p == parent))
+	}
+
+	/// Log `request` and take the first injected fault that applies to it, if any.
+	fn begin(&mut self, request: &MockRequest) -> Option<Fault> {
+		let description = format!("{} /{}", request.method, request.path.join("/"));
+		let fault = self.faults.iter().position(|x| description.starts_with(&x.0)).map(|i| self.faults.remove(i).1);
+
+		self.request_log.push(description);
+		fault
+	}
+
+	fn handle(&mut self, request: MockRequest) -> MockResponse {
+		if route(&request, Method::Post, &["auth", "o2", "token"]).is_some() {
+			return self.token(&request);
+		} else if route(&request, Method::Post, &["auth", "o2", "revoke"]).is_some() {
+			return self.revoke(&request);
+		}
+
+		match request.bearer_token() {
+			Some(ref token) if Some(token) == self.access_token.as_ref() => (),
+			Some(_) => return MockResponse::message(StatusCode::Unauthorized, "Token has expired"),
+			None => return MockResponse::message(StatusCode::Unauthorized, "Authorization header is missing"),
+		}
+
+		if route(&request, Method::Get, &["drive", "v1", "account", "endpoint"]).is_some() {
+			let base = format!("http://{}", request.host());
+			MockResponse::json(StatusCode::Ok, format!("{{\"customerExists\":true,\"contentUrl\":\"{0}/cdproxy/\",\"metadataUrl\":\"{0}/drive/v1/\"}}", base))
+		} else if route(&request, Method::Get, &["drive", "v1", "nodes"]).is_some() {
+			let candidates = self.order.clone();
+			self.list(&request, candidates)
+		} else if route(&request, Method::Post, &["drive", "v1", "nodes"]).is_some() {
+			self.create_folder(&request)
+		} else if let Some(id) = route(&request, Method::Get, &["drive", "v1", "nodes", "*"]) {
+			match self.nodes.get(&id) {
+				Some(node) => MockResponse::node(StatusCode::Ok, node),
+				None => MockResponse::message(StatusCode::NotFound, "Node does not exist"),
+			}
+		} else if let Some(id) = route(&request, Method::Get, &["drive", "v1", "nodes", "*", "children"]) {
+			if !self.nodes.contains_key(&id) {
+				return MockResponse::message(StatusCode::NotFound, "Node does not exist");
+			}
+			let candidates = self.order.iter().filter(|child| self.nodes[*child].parents.contains(&id)).cloned().collect();
+			self.list(&request, candidates)
+		} else if let Some(captured) = route_all(&request, Method::Put, &["drive", "v1", "nodes", "*", "properties", "*", "*"]) {
+			self.set_property(&request, &captured[0], &captured[1], &captured[2])
+		} else if let Some(id) = route(&request, Method::Put, &["drive", "v1", "trash", "*"]) {
+			self.trash(&id)
+		} else if route(&request, Method::Post, &["cdproxy", "nodes"]).is_some() {
+			self.upload(&request)
+		} else if let Some(id) = route(&request, Method::Get, &["cdproxy", "nodes", "*", "content"]) {
+			self.download(&request, &id)
+		} else {
+			MockResponse::message(StatusCode::NotFound, "Unknown resource")
+		}
+	}
+
+	fn token(&mut self, request: &MockRequest) -> MockResponse {
+		let form = form_urlencoded::parse(&request.body);
+		let param = |name: &str| form.iter().find(|&x| x.0 == name).map(|x| x.1.clone());
+
+		if param("client_id") != Some(MOCK_CLIENT_ID.to_owned()) || param("client_secret") != Some(MOCK_CLIENT_SECRET.to_owned()) {
+			return MockResponse::json(StatusCode::Unauthorized, "{\"error\":\"invalid_client\",\"error_description\":\"Client authentication failed\"}".to_owned());
+		}
+
+		let granted = match param("grant_type") {
+			Some(ref grant) if grant == "authorization_code" => param("code") == Some(MOCK_AUTHORIZATION_CODE.to_owned()),
+			Some(ref grant) if grant == "refresh_token" => param("refresh_token").is_some() && param("refresh_token") == self.refresh_token,
+			_ => false,
+		};
+
+		if !granted {
+			return MockResponse::json(StatusCode::BadRequest, "{\"error\":\"invalid_grant\",\"error_description\":\"The request has an invalid grant parameter\"}".to_owned());
+		}
+
+		self.next_token += 1;
+		let access_token = format!("Atza|mock_access_token_{}", self.next_token);
+		let refresh_token = format!("Atzr|mock_refresh_token_{}", self.next_token);
+		self.access_token = Some(access_token.clone());
+		self.refresh_token = Some(refresh_token.clone());
+
+		MockResponse::json(StatusCode::Ok, format!("{{\"access_token\":\"{}\",\"refresh_token\":\"{}\",\"token_type\":\"bearer\",\"expires_in\":{}}}", access_token, refresh_token, self.token_lifetime))
+	}
+
+	/// RFC 7009 token revocation.  Revoking the refresh token revokes the access token too.
+	/// Unknown tokens aren't an error, as the RFC says.
+	fn revoke(&mut self, request: &MockRequest) -> MockResponse {
+		let form = form_urlencoded::parse(&request.body);
+		let param = |name: &str| form.iter().find(|&x| x.0 == name).map(|x| x.1.clone());
+
+		if param("client_id") != Some(MOCK_CLIENT_ID.to_owned()) || param("client_secret") != Some(MOCK_CLIENT_SECRET.to_owned()) {
+			return MockResponse::json(StatusCode::Unauthorized, "{\"error\":\"invalid_client\",\"error_description\":\"Client authentication failed\"}".to_owned());
+		}
+
+		if param("token").is_some() && param("token") == self.refresh_token {
+			self.access_token = None;
+			self.refresh_token = None;
+		}
+
+		MockResponse::json(StatusCode::Ok, "{}".to_owned())
+	}
+
+	/// Filter, sort and page `candidates` (node ids) according to the request's filters, sort,
+	/// startToken and limit.
+	fn list(&self, request: &MockRequest, candidates: Vec<String>) -> MockResponse {
+		let query_filter = match request.query_param("filters") {
+			Some(filters) => match filter::parse(filters) {
+				Ok(filter) => Some(filter),
+				Err(err) => return MockResponse::message(StatusCode::BadRequest, &format!("Invalid filter: {}", err)),
+			},
+			None => None,
+		};
+
+		let mut matching: Vec<&MockNode> = candidates.iter()
+			.map(|id| &self.nodes[id])
+			.filter(|node| match query_filter {
+				// Trashed nodes aren't listed unless the filter asks about status
+				Some(ref query_filter) => query_filter.matches(node) && (node.status == "AVAILABLE" || query_filter.mentions("status")),
+				None => node.status == "AVAILABLE",
+			})
+			.collect();
+
+		if let Some(sort) = request.query_param("sort") {
+			let keys = match parse_sort(sort) {
+				Some(keys) => keys,
+				None => return MockResponse::message(StatusCode::BadRequest, "Invalid sort"),
+			};
+			matching.sort_by(|a, b| compare_nodes(a, b, &keys));
+		}
+
+		let start = match request.query_param("startToken") {
+			Some(token) => match token.parse::<usize>() {
+				Ok(start) => start,
+				Err(_) => return MockResponse::message(StatusCode::BadRequest, "Invalid startToken"),
+			},
+			None => 0,
+		};
+		let limit = match request.query_param("limit") {
+			Some(limit) => match limit.parse::<usize>() {
+				Ok(limit) if limit > 0 => limit,
+				_ => return MockResponse::message(StatusCode::BadRequest, "Invalid limit"),
+			},
+			None => self.page_size,
+		};
+
+		let end = ::std::cmp::min(start + limit, matching.len());
+		let page = if start < end { &matching[start..end] } else { &matching[0..0] };
+
+		MockResponse::json(StatusCode::Ok, json::encode(&NodeListJson {
+			count: matching.len() as u64,
+			nextToken: if end < matching.len() { Some(end.to_string()) } else { None },
+			data: page.iter().map(|node| node.to_json()).collect(),
+		}).unwrap())
+	}
+
+	/// Check that a node called `name` can be created under `parents`.  Returns an error response if not.
+	fn check_create(&self, name: &str, parents: &[String]) -> Option<MockResponse> {
+		if name.is_empty() {
+			return Some(MockResponse::message(StatusCode::BadRequest, "Node name is missing"));
+		}
+
+		for parent in parents {
+			match self.nodes.get(parent) {
+				Some(node) if node.kind == "FOLDER" => (),
+				_ => return Some(MockResponse::message(StatusCode::BadRequest, "Parent does not exist")),
+			}
+
+			if let Some(existing) = self.find_child(parent, name) {
+				return Some(MockResponse::conflict(&existing.id));
+			}
+		}
+
+		None
+	}
+
+	fn create_folder(&mut self, request: &MockRequest) -> MockResponse {
+		let metadata: CreateNodeRequest = match str::from_utf8(&request.body).ok().and_then(|body| json::decode(body).ok()) {
+			Some(metadata) => metadata,
+			None => return MockResponse::message(StatusCode::BadRequest, "Invalid node metadata"),
+		};
+		let parents = metadata.parents.unwrap_or_default();
+
+		if metadata.kind != "FOLDER" {
+			return MockResponse::message(StatusCode::BadRequest, "Only folders can be created on the metadata endpoint");
+		}
+
+		if let Some(response) = self.check_create(&metadata.name, &parents) {
+			return response;
+		}
+
+		let id = self.new_node(&metadata.name, "FOLDER", parents);
+		let node = self.nodes.get_mut(&id).unwrap();
+		node.labels = metadata.labels.unwrap_or_default();
+		node.description = metadata.description;
+		MockResponse::node(StatusCode::Created, node)
+	}
+
+	fn upload(&mut self, request: &MockRequest) -> MockResponse {
+		let parts = match multipart::parse(&request.headers, &request.body) {
+			Some(parts) => parts,
+			None => return MockResponse::message(StatusCode::BadRequest, "Invalid multipart body"),
+		};
+
+		let metadata: CreateNodeRequest = match parts.iter().find(|part| part.name == "metadata").and_then(|part| str::from_utf8(&part.data).ok()).and_then(|s| json::decode(s).ok()) {
+			Some(metadata) => metadata,
+			None => return MockResponse::message(StatusCode::BadRequest, "Invalid node metadata"),
+		};
+		let content = match parts.iter().find(|part| part.name == "content") {
+			Some(part) => part,
+			None => return MockResponse::message(StatusCode::BadRequest, "Content is missing"),
+		};
+		let parents = metadata.parents.unwrap_or_default();
+
+		if metadata.kind != "FILE" {
+			return MockResponse::message(StatusCode::BadRequest, "Only files can be uploaded");
+		}
+
+		if let Some(response) = self.check_create(&metadata.name, &parents) {
+			return response;
+		}
+
+		// Cloud Drive refuses to store the same content twice, unless asked not to check.
+		if request.query_param("suppress") != Some("deduplication") {
+			let mut md5 = Md5::new();
+			md5.input(&content.data);
+			let md5 = Some(md5.result_str().to_lowercase());
+
+			if let Some(duplicate) = self.order.iter().map(|id| &self.nodes[id]).find(|node| node.status == "AVAILABLE" && node.md5() == md5) {
+				return MockResponse::conflict(&duplicate.id);
+			}
+		}
+
+		let id = self.new_node(&metadata.name, "FILE", parents);
+		let node = self.nodes.get_mut(&id).unwrap();
+		node.content = content.data.clone();
+		node.content_type = content.content_type.clone();
+		node.labels = metadata.labels.unwrap_or_default();
+		node.description = metadata.description;
+		MockResponse::node(StatusCode::Created, node)
+	}
+
+	fn download(&self, request: &MockRequest, id: &str) -> MockResponse {
+		match self.nodes.get(id) {
+			Some(node) if node.kind == "FILE" => {
+				let length = node.content.len() as u64;
+				let mut headers = Headers::new();
+				headers.set(header::ContentType(mime!(Application/OctetStream)));
+
+				let (status, start, end) = match request.header("Range") {
+					Some(range) => match parse_range(&range, length) {
+						Some((start, end)) => {
+							headers.set_raw("Content-Range", vec![format!("bytes {}-{}/{}", start, end - 1, length).into_bytes()]);
+							(StatusCode::PartialContent, start, end)
+						},
+						None => return MockResponse::message(StatusCode::RangeNotSatisfiable, "Range not satisfiable"),
+					},
+					None => (StatusCode::Ok, 0, length),
+				};
+
+				MockResponse {
+					status: status,
+					headers: headers,
+					body: node.content[start as usize..end as usize].to_vec(),
+				}
+			},
+			_ => MockResponse::message(StatusCode::NotFound, "Node does not exist"),
+		}
+	}
+
+	fn set_property(&mut self, request: &MockRequest, id: &str, owner: &str, key: &str) -> MockResponse {
+		let property: PropertyRequest = match str::from_utf8(&request.body).ok().and_then(|s| json::decode(s).ok()) {
+			Some(property) => property,
+			None => return MockResponse::message(StatusCode::BadRequest, "Invalid property"),
+		};
+
+		// Apps can only set their own properties
+		if owner != MOCK_CLIENT_ID {
+			return MockResponse::message(StatusCode::Forbidden, "Owner does not match the application");
+		}
+
+		match self.nodes.get_mut(id) {
+			Some(node) => {
+				node.properties.entry(owner.to_owned()).or_insert(BTreeMap::new()).insert(key.to_owned(), property.value.clone());
+				MockResponse::json(StatusCode::Ok, format!("{{\"key\":{},\"value\":{}}}", json::Json::String(key.to_owned()), json::Json::String(property.value)))
+			},
+			None => MockResponse::message(StatusCode::NotFound, "Node does not exist"),
+		}
+	}
+
+	fn trash(&mut self, id: &str) -> MockResponse {
+		match self.nodes.get_mut(id) {
+			Some(node) => {
+				if node.is_root {
+					return MockResponse::message(StatusCode::BadRequest, "The root folder can't be trashed");
+				}
+				node.status = "TRASH".to_owned();
+				node.version += 1;
+				MockResponse::node(StatusCode::Ok, node)
+			},
+			None => MockResponse::message(StatusCode::NotFound, "Node does not exist"),
+		}
+	}
+}
+
+
+struct MockHandler {
+	state: Arc<Mutex<MockState>>,
+}
+
+impl Handler for MockHandler {
+	fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+		let mut body = Vec::new();
+		if req.read_to_end(&mut body).is_err() {
+			*res.status_mut() = StatusCode::BadRequest;
+			let _ = res.send(b"");
+			return;
+		}
+
+		let (path, query) = match req.uri {
+			RequestUri::AbsolutePath(ref uri) => match uri.find('?') {
+				Some(i) => (uri[..i].to_owned(), form_urlencoded::parse(uri[i+1..].as_bytes())),
+				None => (uri.clone(), Vec::new()),
+			},
+			_ => (String::new(), Vec::new()),
+		};
+
+		let request = MockRequest {
+			method: req.method.clone(),
+			path: path.split('/').filter(|s| !s.is_empty()).map(|s| s.to_owned()).collect(),
+			query: query,
+			headers: req.headers.clone(),
+			body: body,
+		};
+
+		let fault = self.state.lock().unwrap().begin(&request);
+
+		if let Some(Fault::Delay(delay)) = fault {
+			thread::sleep(delay);
+		}
+
+		let response = match fault {
+			Some(Fault::Status(ref status)) => MockResponse::message(status.clone(), "Injected fault"),
+			Some(Fault::ExpiredToken) => MockResponse::message(StatusCode::BadRequest, "Token has expired"),
+			Some(Fault::WrongMd5) => self.state.lock().unwrap().handle(request).with_md5(Some("00000000000000000000000000000000")),
+			Some(Fault::NoMd5) => self.state.lock().unwrap().handle(request).with_md5(None),
+			Some(Fault::IgnoreRange) => {
+				let mut request = request;
+				request.headers.remove_raw("Range");
+				self.state.lock().unwrap().handle(request)
+			},
+			_ => self.state.lock().unwrap().handle(request),
+		};
+
+		*res.status_mut() = response.status;
+		*res.headers_mut() = response.headers;
+		// One request per connection, so no connection outlives the server
+		res.headers_mut().set(header::Connection::close());
+
+		match fault {
+			Some(Fault::Truncate) => {
+				// Promise the whole body, send half, and hang up
+				res.headers_mut().set(header::ContentLength(response.body.len() as u64));
+				res.headers_mut().set(header::Connection::close());
+				if let Ok(mut res) = res.start() {
+					let _ = res.write_all(&response.body[..response.body.len() / 2]);
+					let _ = res.flush();
+				}
+			},
+			_ => {
+				let _ = res.send(&response.body);
+			},
+		}
+	}
+}
+
+
+impl MockHandler {
+	/// Answer the one request on `stream`.
+	fn serve(&self, stream: TcpStream) {
+		let addr = match stream.peer_addr() {
+			Ok(addr) => addr,
+			Err(_) => return,
+		};
+		let mut stream = HttpStream(stream);
+		let mut reader_stream = stream.clone();
+		let mut reader = BufReader::new(&mut reader_stream as &mut NetworkStream);
+		let mut writer = BufWriter::new(&mut stream);
+
+		let request = match Request::new(&mut reader, addr) {
+			Ok(request) => request,
+			Err(_) => return,
+		};
+		let mut headers = Headers::new();
+		self.handle(request, Response::new(&mut writer, &mut headers));
+		let _ = writer.flush();
+	}
+}
+
+
+/// A mock Cloud Drive, listening on localhost.
+/// The server keeps running, on its own threads, until the MockServer is dropped.
+pub struct MockServer {
+	state: Arc<Mutex<MockState>>,
+	addr: SocketAddr,
+	/// Tells the accept loop to stop
+	shutdown: Arc<AtomicBool>,
+	accept_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MockServer {
+	/// Start a mock Cloud Drive on a free localhost port.  The drive starts out empty, except
+	/// for the root folder.
+	pub fn start() -> MockServer {
+		let state = Arc::new(Mutex::new(MockState::new()));
+		let handler = Arc::new(MockHandler {
+			state: state.clone(),
+		});
+		let shutdown = Arc::new(AtomicBool::new(false));
+
+		// hyper's own Server can't be stopped, so connections are accepted here instead
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let accept_thread = {
+			let shutdown = shutdown.clone();
+			thread::spawn(move || {
+				for stream in listener.incoming() {
+					if shutdown.load(atomic::Ordering::SeqCst) {
+						break;
+					}
+					if let Ok(stream) = stream {
+						let handler = handler.clone();
+						thread::spawn(move || handler.serve(stream));
+					}
+				}
+			})
+		};
+
+		MockServer {
+			state: state,
+			addr: addr,
+			shutdown: shutdown,
+			accept_thread: Some(accept_thread),
+		}
+	}
+
+	/// "http://127.0.0.1:port" + path
+	pub fn url(&self, path: &str) -> String {
+		format!("http://{}{}", self.addr, path)
+	}
+
+	/// A `ClientBuilder` pointed at this server, which authorizes against it without prompting.
+	/// Nothing is persisted: the token store and node cache are in memory unless configured otherwise.
+	pub fn client_builder(&self) -> ClientBuilder {
+		ClientBuilder::new(MOCK_CLIENT_ID, MOCK_CLIENT_SECRET)
+			.auth_prompt(Box::new(MockAuthPrompt))
+			.authorize_url(&self.url("/ap/oa"))
+			.token_url(&self.url("/auth/o2/token"))
+			.endpoint_url(&self.url("/drive/v1/account/endpoint"))
+	}
+
+	/// The id of the root folder.
+	pub fn root_id(&self) -> String {
+		self.state.lock().unwrap().root_id.clone()
+	}
+
+	/// A snapshot of the node with this id.
+	pub fn node(&self, id: &str) -> Option<MockNode> {
+		self.state.lock().unwrap().nodes.get(id).cloned()
+	}
+
+	/// A snapshot of every node, in the order they were created.
+	pub fn nodes(&self) -> Vec<MockNode> {
+		let state = self.state.lock().unwrap();
+		state.order.iter().map(|id| state.nodes[id].clone()).collect()
+	}
+
+	/// Add a node (as if uploaded by someone else) and return its id.
+	/// `content` is None for folders.
+	pub fn add_node(&self, parent: &str, name: &str, content: Option<&[u8]>) -> String {
+		let mut state = self.state.lock().unwrap();
+		let kind = if content.is_some() { "FILE" } else { "FOLDER" };
+		let id = state.new_node(name, kind, vec![parent.to_owned()]);
+		if let Some(content) = content {
+			state.nodes.get_mut(&id).unwrap().content = content.to_vec();
+		}
+		id
+	}
+
+	/// Replace the content of file `id` (as if someone else overwrote it), bumping its version.
+	pub fn overwrite(&self, id: &str, content: &[u8]) {
+		let mut state = self.state.lock().unwrap();
+		let node = state.nodes.get_mut(id).unwrap();
+		node.content = content.to_vec();
+		node.version += 1;
+	}
+
+	/// Set how many nodes a listing returns per page when the request doesn't say.
+	pub fn set_page_size(&self, page_size: usize) {
+		self.state.lock().unwrap().page_size = page_size;
+	}
+
+	/// Set how long (in seconds) tokens issued from now on say they're valid for.
+	pub fn set_token_lifetime(&self, lifetime: u64) {
+		self.state.lock().unwrap().token_lifetime = lifetime;
+	}
+
+	/// True if a refresh token has been handed out and not revoked.
+	pub fn has_refresh_token(&self) -> bool {
+		self.state.lock().unwrap().refresh_token.is_some()
+	}
+
+	/// Invalidate the current access token, as if it had expired.
+	pub fn expire_access_token(&self) {
+		self.state.lock().unwrap().access_token = None;
+	}
+
+	/// Queue up `fault` for the next request that starts with `request`, which is matched against
+	/// "METHOD /path" (query strings stripped, e.g. "GET /drive/v1/nodes").  Faults are used once
+	/// each, in the order they were injected, so several for the same request script a sequence.
+	pub fn inject_fault(&self, request: &str, fault: Fault) {
+		self.state.lock().unwrap().faults.push((request.to_owned(), fault));
+	}
+
+	/// How many injected faults haven't been triggered yet.
+	pub fn pending_faults(&self) -> usize {
+		self.state.lock().unwrap().faults.len()
+	}
+
+	/// Every request received so far, as "METHOD /path" (query strings stripped).
+	pub fn request_log(&self) -> Vec<String> {
+		self.state.lock().unwrap().request_log.clone()
+	}
+}
+
+impl Drop for MockServer {
+	fn drop(&mut self) {
+		self.shutdown.store(true, atomic::Ordering::SeqCst);
+
+		// Wake the accept loop up so it sees the flag
+		if TcpStream::connect(self.addr).is_ok() {
+			if let Some(accept_thread) = self.accept_thread.take() {
+				let _ = accept_thread.join();
+			}
+		}
+	}
+}
+
+
+/// Just enough multipart/form-data parsing to handle our own uploads.
+mod multipart {
+	use hyper::header::Headers;
+	use std::str;
+
+	pub struct Part {
+		pub name: String,
+		pub content_type: Option<String>,
+		pub data: Vec<u8>,
+	}
+
+	pub fn parse(headers: &Headers, body: &[u8]) -> Option<Vec<Part>> {
+		let content_type = match headers.get_raw("Content-Type") {
+			Some(values) if values.len() > 0 => String::from_utf8_lossy(&values[0]).into_owned(),
+			_ => return None,
+		};
+		let boundary = match content_type.split(';').map(|s| s.trim()).find(|s| s.starts_with("boundary=")) {
+			Some(param) => param["boundary=".len()..].trim_matches('"').to_owned(),
+			None => return None,
+		};
+		let delimiter = format!("--{}", boundary).into_bytes();
+
+		let mut parts = Vec::new();
+		let mut pieces = split(body, &delimiter);
+
+		// Everything before the first delimiter is preamble
+		if pieces.len() < 2 {
+			return None;
+		}
+		pieces.remove(0);
+
+		for piece in pieces {
+			// The closing delimiter is followed by "--"
+			if piece.starts_with(b"--") {
+				break;
+			}
+
+			let piece = strip_prefix(piece, b"\r\n");
+			let piece = strip_suffix(piece, b"\r\n");
+			let header_end = match find(piece, b"\r\n\r\n") {
+				Some(i) => i,
+				None => return None,
+			};
+			let part_headers = String::from_utf8_lossy(&piece[..header_end]).into_owned();
+
+			let mut name = None;
+			let mut content_type = None;
+
+			for line in part_headers.split("\r\n") {
+				let lower = line.to_lowercase();
+				if lower.starts_with("content-disposition:") {
+					name = line.split(';').map(|s| s.trim()).find(|s| s.starts_with("name=")).map(|s| s["name=".len()..].trim_matches('"').to_owned());
+				} else if lower.starts_with("content-type:") {
+					content_type = Some(line["content-type:".len()..].trim().to_owned());
+				}
+			}
+
+			parts.push(Part {
+				name: match name { Some(name) => name, None => return None },
+				content_type: content_type,
+				data: piece[header_end+4..].to_vec(),
+			});
+		}
+
+		Some(parts)
+	}
+
+	fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+		if needle.len() > haystack.len() {
+			return None;
+		}
+		(0..haystack.len() - needle.len() + 1).find(|&i| &haystack[i..i+needle.len()] == needle)
+	}
+
+	fn split<'a>(mut haystack: &'a [u8], needle: &[u8]) -> Vec<&'a [u8]> {
+		let mut pieces = Vec::new();
+		while let Some(i) = find(haystack, needle) {
+			pieces.push(&haystack[..i]);
+			haystack = &haystack[i+needle.len()..];
+		}
+		pieces.push(haystack);
+		pieces
+	}
+
+	fn strip_prefix<'a>(data: &'a [u8], prefix: &[u8]) -> &'a [u8] {
+		if data.starts_with(prefix) { &data[prefix.len()..] } else { data }
+	}
+
+	fn strip_suffix<'a>(data: &'a [u8], suffix: &[u8]) -> &'a [u8] {
+		if data.ends_with(suffix) { &data[..data.len()-suffix.len()] } else { data }
+	}
+}
+
+
+/// Evaluates the Lucene-like query syntax of the `filters` parameter against mock nodes.
+/// Supports `field:value` terms, AND/OR/NOT, parentheses, backslash escapes and trailing `*`
+/// prefix wildcards.
+mod filter {
+	use super::MockNode;
+
+	pub enum Filter {
+		Term {
+			field: String,
+			value: String,
+			prefix: bool,
+		},
+		/// field:[min TO max], where None is "*"
+		Range {
+			field: String,
+			min: Option<String>,
+			max: Option<String>,
+			min_inclusive: bool,
+			max_inclusive: bool,
+		},
+		And(Box<Filter>, Box<Filter>),
+		Or(Box<Filter>, Box<Filter>),
+		Not(Box<Filter>),
+	}
+
+	impl Filter {
+		pub fn matches(&self, node: &MockNode) -> bool {
+			match *self {
+				Filter::Term { ref field, ref value, prefix } => {
+					let compare = |actual: &str| if prefix { actual.starts_with(&value[..]) } else { actual == &value[..] };
+
+					match &field[..] {
+						"name" => compare(&node.name[..]),
+						"kind" => compare(&node.kind[..]),
+						"status" => compare(&node.status[..]),
+						"isRoot" => compare(if node.is_root { "true" } else { "false" }),
+						"parents" => node.parents.iter().any(|parent| compare(&parent[..])),
+						"labels" => node.labels.iter().any(|label| compare(&label[..])),
+						"description" => node.description.as_ref().map_or(false, |description| compare(&description[..])),
+						"contentProperties.md5" => node.md5().map_or(false, |md5| compare(&md5[..])),
+						"contentProperties.contentType" => node.kind == "FILE" && compare(node.content_type.as_ref().map(|s| &s[..]).unwrap_or("application/octet-stream")),
+						_ => false,
+					}
+				},
+				Filter::Range { ref field, ref min, ref max, min_inclusive, max_inclusive } => {
+					let actual = match &field[..] {
+						"contentProperties.size" => node.md5().map(|_| node.content.len().to_string()),
+						"createdDate" => Some(node.created_date.clone()),
+						"modifiedDate" => Some(node.modified_date.clone()),
+						_ => None,
+					};
+					let actual = match actual.and_then(|actual| range_key(field, &actual)) {
+						Some(actual) => actual,
+						None => return false,
+					};
+					let above_min = match min.as_ref().and_then(|min| range_key(field, min)) {
+						Some(ref min) => if min_inclusive { actual >= *min } else { actual > *min },
+						None => true,
+					};
+					let below_max = match max.as_ref().and_then(|max| range_key(field, max)) {
+						Some(ref max) => if max_inclusive { actual <= *max } else { actual < *max },
+						None => true,
+					};
+
+					above_min && below_max
+				},
+				Filter::And(ref a, ref b) => a.matches(node) && b.matches(node),
+				Filter::Or(ref a, ref b) => a.matches(node) || b.matches(node),
+				Filter::Not(ref a) => !a.matches(node),
+			}
+		}
+
+		/// True if any term of this filter is on `field`.
+		pub fn mentions(&self, field: &str) -> bool {
+			match *self {
+				Filter::Term { field: ref term_field, .. } | Filter::Range { field: ref term_field, .. } => term_field == field,
+				Filter::And(ref a, ref b) | Filter::Or(ref a, ref b) => a.mentions(field) || b.mentions(field),
+				Filter::Not(ref a) => a.mentions(field),
+			}
+		}
+	}
+
+	/// `value` in a form that compares the way `field` should: sizes as zero-padded numbers, dates
+	/// as they are.
+	fn range_key(field: &str, value: &str) -> Option<String> {
+		if field == "contentProperties.size" {
+			value.parse::<u64>().ok().map(|size| format!("{:020}", size))
+		} else {
+			Some(value.to_owned())
+		}
+	}
+
+	#[derive(Debug, PartialEq)]
+	enum Token {
+		LParen,
+		RParen,
+		And,
+		Or,
+		Not,
+		Term(String, String, bool),
+		Range(String, Option<String>, Option<String>, bool, bool),
+	}
+
+	/// Read the rest of a range, after its opening bracket: "min TO max]" (or "}" if exclusive).
+	/// Bounds are bare words, as in Amazon's examples.  Returns (min, max, max_inclusive).
+	fn read_range<I: Iterator<Item=char>>(chars: &mut I) -> Result<(Option<String>, Option<String>, bool), String> {
+		let mut words: Vec<String> = Vec::new();
+		let mut word = String::new();
+		let max_inclusive;
+
+		loop {
+			match chars.next() {
+				Some(c) if c == ']' || c == '}' || c.is_whitespace() => {
+					if !word.is_empty() {
+						words.push(word.clone());
+						word.clear();
+					}
+					if c == ']' || c == '}' {
+						max_inclusive = c == ']';
+						break;
+					}
+				},
+				Some(c) if c == '"' || c == '\\' || c == '[' || c == '{' => return Err(format!("unexpected '{}' in range", c)),
+				Some(c) => word.push(c),
+				None => return Err("unterminated range".to_owned()),
+			}
+		}
+
+		if words.len() != 3 || words[1] != "TO" {
+			return Err(format!("expected [min TO max], found {:?}", words));
+		}
+
+		let bound = |word: &String| if word == "*" { None } else { Some(word.clone()) };
+		Ok((bound(&words[0]), bound(&words[2]), max_inclusive))
+	}
+
+	fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+		let mut tokens = Vec::new();
+		let mut chars = s.chars().peekable();
+
+		loop {
+			match chars.peek().cloned() {
+				None => break,
+				Some(c) if c.is_whitespace() => { chars.next(); },
+				Some('(') => { chars.next(); tokens.push(Token::LParen); },
+				Some(')') => { chars.next(); tokens.push(Token::RParen); },
+				Some(_) => {
+					let mut field: Option<String> = None;
+					let mut word = String::new();
+					let mut prefix = false;
+					let mut range = None;
+
+					while let Some(c) = chars.peek().cloned() {
+						if c.is_whitespace() || c == '(' || c == ')' {
+							break;
+						}
+						chars.next();
+
+						if prefix {
+							return Err(format!("unescaped '*' in the middle of a value in {:?}", s));
+						}
+
+						match c {
+							'\\' => match chars.next() {
+								Some(escaped) => word.push(escaped),
+								None => return Err("trailing backslash".to_owned()),
+							},
+							':' if field.is_none() => {
+								field = Some(word.clone());
+								word.clear();
+
+								match chars.peek().cloned() {
+									Some(open) if open == '[' || open == '{' => {
+										chars.next();
+										let (min, max, max_inclusive) = try!(read_range(&mut chars));
+										range = Some((min, max, open == '[', max_inclusive));
+										break;
+									},
+									_ => (),
+								}
+							},
+							'*' if field.is_some() => prefix = true,
+							':' | '*' | '"' | '\'' | '[' | ']' | '{' | '}' | '^' | '~' | '?' | '!' | '+' | '-' | '&' | '|' => return Err(format!("unescaped '{}' in {:?}", c, s)),
+							_ => word.push(c),
+						}
+					}
+
+					tokens.push(match (field, range) {
+						(Some(field), Some((min, max, min_inclusive, max_inclusive))) => Token::Range(field, min, max, min_inclusive, max_inclusive),
+						(Some(field), None) => Token::Term(field, word, prefix),
+						(None, _) => match &word[..] {
+							"AND" => Token::And,
+							"OR" => Token::Or,
+							"NOT" => Token::Not,
+							_ => return Err(format!("expected field:value, found {:?}", word)),
+						},
+					});
+				},
+			}
+		}
+
+		Ok(tokens)
+	}
+
+	pub fn parse(s: &str) -> Result<Filter, String> {
+		let tokens = try!(tokenize(s));
+		let mut pos = 0;
+		let filter = try!(parse_or(&tokens, &mut pos));
+
+		if pos != tokens.len() {
+			return Err(format!("unexpected {:?}", tokens[pos]));
+		}
+
+		Ok(filter)
+	}
+
+	fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Filter, String> {
+		let mut filter = try!(parse_and(tokens, pos));
+
+		while *pos < tokens.len() && tokens[*pos] == Token::Or {
+			*pos += 1;
+			filter = Filter::Or(Box::new(filter), Box::new(try!(parse_and(tokens, pos))));
+		}
+
+		Ok(filter)
+	}
+
+	fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Filter, String> {
+		let mut filter = try!(parse_not(tokens, pos));
+
+		while *pos < tokens.len() && tokens[*pos] == Token::And {
+			*pos += 1;
+			filter = Filter::And(Box::new(filter), Box::new(try!(parse_not(tokens, pos))));
+		}
+
+		Ok(filter)
+	}
+
+	fn parse_not(tokens: &[Token], pos: &mut usize) -> Result<Filter, String> {
+		if *pos < tokens.len() && tokens[*pos] == Token::Not {
+			*pos += 1;
+			return Ok(Filter::Not(Box::new(try!(parse_not(tokens, pos)))));
+		}
+
+		parse_primary(tokens, pos)
+	}
+
+	fn parse_primary(tokens: &[Token], pos: &mut usize) -> Result<Filter, String> {
+		match tokens.get(*pos) {
+			Some(&Token::LParen) => {
+				*pos += 1;
+				let filter = try!(parse_or(tokens, pos));
+				match tokens.get(*pos) {
+					Some(&Token::RParen) => {
+						*pos += 1;
+						Ok(filter)
+					},
+					_ => Err("missing ')'".to_owned()),
+				}
+			},
+			Some(&Token::Term(ref field, ref value, prefix)) => {
+				*pos += 1;
+				Ok(Filter::Term {
+					field: field.clone(),
+					value: value.clone(),
+					prefix: prefix,
+				})
+			},
+			Some(&Token::Range(ref field, ref min, ref max, min_inclusive, max_inclusive)) => {
+				*pos += 1;
+				Ok(Filter::Range {
+					field: field.clone(),
+					min: min.clone(),
+					max: max.clone(),
+					min_inclusive: min_inclusive,
+					max_inclusive: max_inclusive,
+				})
+			},
+			Some(token) => Err(format!("unexpected {:?}", token)),
+			None => Err("unexpected end of filter".to_owned()),
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::MockServer;
+	use super::filter;
+	use error::Error;
+	use node::{NodeKind, NodeStatus};
+	use list::{ListOptions, SortField, SortOrder};
+	use std::net::TcpStream;
+
+	#[test]
+	fn test_filter_parse() {
+		assert!(filter::parse("kind:FOLDER AND isRoot:true").is_ok());
+		assert!(filter::parse("(name:a OR name:b) AND NOT status:TRASH").is_ok());
+		assert!(filter::parse("name:hello\\ world").is_ok());
+		assert!(filter::parse("name:hello world").is_err());
+		assert!(filter::parse("name:it's").is_err());
+		assert!(filter::parse("name:(unbalanced").is_err());
+		assert!(filter::parse("contentProperties.size:[10 TO *] AND modifiedDate:{2016-01-01T00:00:00Z TO 2017-01-01T00:00:00Z}").is_ok());
+		assert!(filter::parse("contentProperties.size:[10 *]").is_err());
+		assert!(filter::parse("contentProperties.size:[10 TO *").is_err());
+		assert!(filter::parse("modifiedDate:[\"x] OR name:a\" TO *]").is_ok());
+		assert!(filter::parse("modifiedDate:[x] OR name:a TO *]").is_err());
+	}
+
+	#[test]
+	fn test_mock_server() {
+		let server = MockServer::start();
+		server.set_page_size(2);
+		let mut client = server.client_builder().maximum_retry(1).build().unwrap();
+
+		let folder = client.mkdir_all(None, "/a/b").unwrap();
+		assert_eq!(client.mkdir(Some(&folder), "c").unwrap(), client.mkdir(Some(&folder), "c").unwrap());
+
+		let file = client.upload(Some(&folder), "file", b"some data", None).unwrap();
+		assert_eq!(client.download(&file).unwrap(), b"some data");
+		assert_eq!(client.find_path(None, "/a/b/file").unwrap(), Some(file.clone()));
+
+		match client.upload(Some(&folder), "file", b"other data", None) {
+			Err(Error::NodeExists) => (),
+			_ => panic!("uploading over an existing name should conflict"),
+		}
+
+		// Enough entries to need several pages
+		for i in 0..4 {
+			client.upload(Some(&folder), &format!("file{}", i), format!("data{}", i).as_bytes(), None).unwrap();
+		}
+		assert_eq!(client.ls(&folder).unwrap().len(), 6);
+
+		client.rm(&file).unwrap();
+		assert_eq!(server.node(&file.0).unwrap().status, "TRASH");
+		assert_eq!(client.ls(&folder).unwrap().len(), 5);
+
+		// Sorting applies across pages
+		let options = ListOptions::new().kind(NodeKind::File).sort(SortField::Name, SortOrder::Descending);
+		let names: Vec<String> = client.ls_with(&folder, &options).unwrap().into_iter().map(|node| node.name.unwrap()).collect();
+		assert_eq!(names, vec!["file3", "file2", "file1", "file0"]);
+
+		let trashed = client.ls_with(&folder, &ListOptions::new().status(NodeStatus::Trash)).unwrap();
+		assert_eq!(trashed.len(), 1);
+		assert_eq!(trashed[0].id, file);
+
+		// Expired tokens are refreshed transparently
+		server.expire_access_token();
+		assert_eq!(client.ls(&folder).unwrap().len(), 5);
+	}
+
+	#[test]
+	fn test_drop_stops_server() {
+		let server = MockServer::start();
+		let addr = server.addr;
+		drop(server);
+		assert!(TcpStream::connect(addr).is_err());
+	}
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::str::FromStr;
+use std::collections::BTreeMap;
+use super::NodeId;
+
+
+/// What kind of thing a node is.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum NodeKind {
+	File,
+	Folder,
+	/// Extra data attached to a file by Amazon (e.g. a video's transcode)
+	Asset,
+	/// Anything Amazon adds in the future
+	Other(String),
+}
+
+/// Never fails; kinds we don't know become `NodeKind::Other`.
+impl FromStr for NodeKind {
+	type Err = ();
+
+	fn from_str(kind: &str) -> ::std::result::Result<NodeKind, ()> {
+		Ok(match kind {
+			"FILE" => NodeKind::File,
+			"FOLDER" => NodeKind::Folder,
+			"ASSET" => NodeKind::Asset,
+			_ => NodeKind::Other(kind.to_owned()),
+		})
+	}
+}
+
+impl NodeKind {
+	/// As Amazon spells it
+	pub fn as_str(&self) -> &str {
+		match *self {
+			NodeKind::File => "FILE",
+			NodeKind::Folder => "FOLDER",
+			NodeKind::Asset => "ASSET",
+			NodeKind::Other(ref kind) => kind,
+		}
+	}
+}
+
+impl fmt::Display for NodeKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+
+/// Where a node is in its lifecycle.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum NodeStatus {
+	Available,
+	/// In the trash (see `Client::rm`)
+	Trash,
+	/// Deleted for good; only seen briefly after the trash is emptied
+	Purged,
+	/// Still being processed after upload
+	Pending,
+	/// Anything Amazon adds in the future
+	Other(String),
+}
+
+/// Never fails; statuses we don't know become `NodeStatus::Other`.
+impl FromStr for NodeStatus {
+	type Err = ();
+
+	fn from_str(status: &str) -> ::std::result::Result<NodeStatus, ()> {
+		Ok(match status {
+			"AVAILABLE" => NodeStatus::Available,
+			"TRASH" => NodeStatus::Trash,
+			"PURGED" => NodeStatus::Purged,
+			"PENDING" => NodeStatus::Pending,
+			_ => NodeStatus::Other(status.to_owned()),
+		})
+	}
+}
+
+impl NodeStatus {
+	/// As Amazon spells it
+	pub fn as_str(&self) -> &str {
+		match *self {
+			NodeStatus::Available => "AVAILABLE",
+			NodeStatus::Trash => "TRASH",
+			NodeStatus::Purged => "PURGED",
+			NodeStatus::Pending => "PENDING",
+			NodeStatus::Other(ref status) => status,
+		}
+	}
+}
+
+impl fmt::Display for NodeStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+
+/// A node's metadata.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Node {
+	pub id: NodeId,
+	/// None for the root folder
+	pub name: Option<String>,
+	pub kind: NodeKind,
+	pub parents: Vec<NodeId>,
+	pub status: NodeStatus,
+	/// Size of the file's content in bytes.  None for folders.
+	pub size: Option<u64>,
+	/// MD5 of the file's content, as lowercase hex.  None for folders.
+	pub md5: Option<String>,
+	/// MIME type of the file's content.  None for folders.
+	pub content_type: Option<String>,
+	/// RFC 3339, e.g. "2016-03-07T22:31:12.173Z"
+	pub created_date: String,
+	/// RFC 3339, e.g. "2016-03-07T22:31:12.173Z"
+	pub modified_date: String,
+	/// Incremented by Amazon on every change
+	pub version: u64,
+	pub labels: Vec<String>,
+	pub description: Option<String>,
+	/// Properties set by apps, keyed by owner (the app's client_id) and then by key.  Apps only
+	/// see their own.  See `Client::set_property`.
+	pub properties: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Node {
+	pub fn is_file(&self) -> bool {
+		self.kind == NodeKind::File
+	}
+
+	pub fn is_folder(&self) -> bool {
+		self.kind == NodeKind::Folder
+	}
+}
+
+
+#[derive(RustcDecodable, Debug)]
+#[allow(non_snake_case)]
+pub struct ContentPropertiesJson {
+	pub size: Option<u64>,
+	pub md5: Option<String>,
+	pub contentType: Option<String>,
+}
+
+/// A node as the metadata API returns it.  Everything but id and kind is optional, since Amazon
+/// leaves out what doesn't apply.
+#[derive(RustcDecodable, Debug)]
+#[allow(non_snake_case)]
+pub struct NodeJson {
+	pub id: String,
+	pub name: Option<String>,
+	pub kind: String,
+	pub parents: Option<Vec<String>>,
+	pub status: Option<String>,
+	pub createdDate: Option<String>,
+	pub modifiedDate: Option<String>,
+	pub version: Option<u64>,
+	pub labels: Option<Vec<String>>,
+	pub description: Option<String>,
+	pub properties: Option<BTreeMap<String, BTreeMap<String, String>>>,
+	pub contentProperties: Option<ContentPropertiesJson>,
+}
+
+impl NodeJson {
+	pub fn into_node(self) -> Node {
+		let (size, md5, content_type) = match self.contentProperties {
+			Some(properties) => (properties.size, properties.md5.map(|md5| md5.to_lowercase()), properties.contentType),
+			None => (None, None, None),
+		};
+
+		Node {
+			id: NodeId(self.id),
+			name: self.name,
+			kind: NodeKind::from_str(&self.kind).unwrap(),
+			parents: self.parents.unwrap_or_default().into_iter().map(NodeId).collect(),
+			status: self.status.map_or(NodeStatus::Available, |status| NodeStatus::from_str(&status).unwrap()),
+			size: size,
+			md5: md5,
+			content_type: content_type,
+			created_date: self.createdDate.unwrap_or_default(),
+			modified_date: self.modifiedDate.unwrap_or_default(),
+			version: self.version.unwrap_or(0),
+			labels: self.labels.unwrap_or_default(),
+			description: self.description,
+			properties: self.properties.unwrap_or_default(),
+		}
+	}
+}
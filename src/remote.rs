@@ -0,0 +1,150 @@
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+use error::{Result, Error};
+use super::{Client, NodeId};
+
+
+/// How much `RemoteFile` fetches at a time, unless told otherwise.
+pub const DEFAULT_READ_AHEAD: usize = 1024 * 1024;
+
+
+/// A file on the Cloud Drive, opened for reading with `Client::open`.  Reads fetch only the part
+/// of the file they need (plus some read-ahead), so seeking around a large file is cheap.
+///
+/// A file overwritten part way through reading can give a mix of old and new content, unless
+/// `set_check_version` is turned on.
+///
+/// I/O errors are passed through as they are.  Other errors become `io::ErrorKind::Other`, with
+/// the `Error` as the payload, so callers can get it back:
+///
+/// ```ignore
+/// match err.get_ref().and_then(|err| err.downcast_ref::<acd::Error>()) {
+///     Some(&acd::Error::NodeChanged) => ...
+/// }
+/// ```
+pub struct RemoteFile<'a> {
+	client: &'a mut Client,
+	id: NodeId,
+	size: u64,
+	/// The file's version when it was opened
+	version: u64,
+	position: u64,
+	/// Bytes of the file starting at `buffer_start`
+	buffer: Vec<u8>,
+	buffer_start: u64,
+	read_ahead: usize,
+	/// Whether each fetch checks that the file's version is still `version`
+	check_version: bool,
+}
+
+impl<'a> RemoteFile<'a> {
+	pub fn open(client: &'a mut Client, id: &NodeId) -> Result<RemoteFile<'a>> {
+		let node = try!(client.get_node(id));
+		if !node.is_file() {
+			return Err(Error::NotAFile);
+		}
+
+		Ok(RemoteFile {
+			client: client,
+			id: id.clone(),
+			size: node.size.unwrap_or(0),
+			version: node.version,
+			position: 0,
+			buffer: Vec::new(),
+			buffer_start: 0,
+			read_ahead: DEFAULT_READ_AHEAD,
+			check_version: false,
+		})
+	}
+
+	/// Size of the file in bytes, as of when it was opened.
+	pub fn len(&self) -> u64 {
+		self.size
+	}
+
+	/// Whether the file was empty when it was opened.
+	pub fn is_empty(&self) -> bool {
+		self.size == 0
+	}
+
+	/// Fetch at least `read_ahead` bytes at a time (as long as the file goes on that long).
+	/// Smaller values mean less wasted download for scattered reads, larger values fewer requests
+	/// for sequential ones.
+	pub fn set_read_ahead(&mut self, read_ahead: usize) {
+		self.read_ahead = read_ahead;
+	}
+
+	/// Check after each fetch that the file's version is still the one that was opened, so that
+	/// reading a file that's been overwritten fails with `Error::NodeChanged`.  Off by default,
+	/// since it costs a metadata request per fetch.
+	pub fn set_check_version(&mut self, check_version: bool) {
+		self.check_version = check_version;
+	}
+
+	/// Refill the buffer with `wanted` bytes (or more) from the current position.
+	fn fill_buffer(&mut self, wanted: usize) -> Result<()> {
+		let length = cmp::max(wanted, self.read_ahead) as u64;
+
+		let buffer = try!(self.client.download_range(&self.id, self.position, Some(length)));
+
+		// Versions only go up, so if it's unchanged now, so was what was downloaded
+		if self.check_version && try!(self.client.get_node(&self.id)).version != self.version {
+			return Err(Error::NodeChanged);
+		}
+
+		self.buffer = buffer;
+		self.buffer_start = self.position;
+		Ok(())
+	}
+}
+
+impl<'a> Read for RemoteFile<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.position >= self.size || buf.is_empty() {
+			return Ok(0);
+		}
+
+		let buffer_end = self.buffer_start + self.buffer.len() as u64;
+
+		if self.position < self.buffer_start || self.position >= buffer_end {
+			try!(self.fill_buffer(buf.len()).map_err(|err| match err {
+				Error::Io(err) => err,
+				err => io::Error::new(io::ErrorKind::Other, err),
+			}));
+		}
+
+		let offset = (self.position - self.buffer_start) as usize;
+		let n = cmp::min(buf.len(), self.buffer.len().saturating_sub(offset));
+		buf[..n].clone_from_slice(&self.buffer[offset..offset + n]);
+		self.position += n as u64;
+		Ok(n)
+	}
+}
+
+impl<'a> Seek for RemoteFile<'a> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let position = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => offset_by(self.size, offset),
+			SeekFrom::Current(offset) => offset_by(self.position, offset),
+		};
+
+		match position {
+			Some(position) => {
+				self.position = position;
+				Ok(position)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+		}
+	}
+}
+
+
+/// `base + offset`, if that's a valid position.
+fn offset_by(base: u64, offset: i64) -> Option<u64> {
+	if offset >= 0 {
+		base.checked_add(offset as u64)
+	} else {
+		base.checked_sub(offset.wrapping_neg() as u64)
+	}
+}
@@ -1,13 +1,21 @@
 use mime::Mime;
-use hyper::{self, header};
-use hyper::client::request::Request;
-use hyper::http::Protocol;
-use multipart::client::Multipart;
+use hyper;
 use url::{Url, form_urlencoded};
-use url::ParseError as UrlError;
-use std::io::{Cursor, Write};
 use std::borrow::Borrow;
+use std::io::{self, Read};
 use std::time::Duration;
+use rand::{self, Rng};
+use error::{Result, Error};
+use transport::{Transport, BodySink, HttpRequest, HttpResponse};
+
+
+/// Connection settings applied to every request.
+#[derive(Clone)]
+pub struct RequestSettings {
+	pub read_timeout: Option<Duration>,
+	pub write_timeout: Option<Duration>,
+	pub user_agent: Option<String>,
+}
 
 
 #[derive(Clone)]
@@ -18,39 +26,43 @@ pub struct RestBuilder {
 	body: Option<Vec<u8>>,
 	multiparts: Vec<RestBuilderMultipartPart>,
 	content_type: Option<Mime>,
+	/// Start and (exclusive) end of the byte range to ask for
+	range: Option<(u64, Option<u64>)>,
 }
 
 #[derive(Clone)]
 struct RestBuilderMultipartPart {
 	name: String,
-	data: Vec<u8>,
+	/// None for the part added by `multipart_stream`
+	data: Option<Vec<u8>>,
 	filename: Option<String>,
 	content_type: Option<Mime>,
 }
 
 
 impl RestBuilder {
-	pub fn get(url: &str) -> RestBuilder {
+	pub fn get(url: &str) -> Result<RestBuilder> {
 		RestBuilder::new(hyper::method::Method::Get, url)
 	}
 
-	pub fn post(url: &str) -> RestBuilder {
+	pub fn post(url: &str) -> Result<RestBuilder> {
 		RestBuilder::new(hyper::method::Method::Post, url)
 	}
 
-	pub fn put(url: &str) -> RestBuilder {
+	pub fn put(url: &str) -> Result<RestBuilder> {
 		RestBuilder::new(hyper::method::Method::Put, url)
 	}
 
-	pub fn new(method: hyper::method::Method, url: &str) -> RestBuilder {
-		RestBuilder {
+	pub fn new(method: hyper::method::Method, url: &str) -> Result<RestBuilder> {
+		Ok(RestBuilder {
 			method: method,
-			url: Url::parse(url).unwrap(),
+			url: try!(check_url(url)),
 			access_token: None,
 			body: None,
 			multiparts: Vec::new(),
 			content_type: None,
-		}
+			range: None,
+		})
 	}
 
 	pub fn url_push(mut self, piece: &str) -> RestBuilder {
@@ -73,6 +85,12 @@ impl RestBuilder {
 		self
 	}
 
+	/// Only ask for bytes `start` up to (but not including) `end`, or to the end if None.
+	pub fn range(mut self, start: u64, end: Option<u64>) -> RestBuilder {
+		self.range = Some((start, end));
+		self
+	}
+
 	pub fn body(mut self, body: &[u8]) -> RestBuilder {
 		self.body = Some(body.to_vec());
 		self
@@ -101,66 +119,212 @@ impl RestBuilder {
 	pub fn multipart_data(mut self, name: &str, data: &[u8], filename: Option<String>, content_type: Option<Mime>) -> RestBuilder {
 		self.multiparts.push(RestBuilderMultipartPart {
 			name: name.to_owned(),
-			data: data.to_vec(),
+			data: Some(data.to_vec()),
 			filename: filename,
 			content_type: content_type,
 		});
 		self
 	}
 
-	pub fn send(self, protocol: &Box<Protocol>) -> hyper::error::Result<hyper::client::response::Response> {
-		let message = {
-			let (host, port) = try!(get_host_and_port(&self.url));
-			try!(protocol.new_message(&host, port, &*self.url.scheme))
-		};
+	/// Add a part whose data isn't known yet; it's read from the reader given to `send_streaming`.
+	/// Must be the last part.
+	pub fn multipart_stream(mut self, name: &str, filename: Option<String>, content_type: Option<Mime>) -> RestBuilder {
+		self.multiparts.push(RestBuilderMultipartPart {
+			name: name.to_owned(),
+			data: None,
+			filename: filename,
+			content_type: content_type,
+		});
+		self
+	}
 
-		let mut request = try!(Request::with_message(self.method, self.url, message));
+	pub fn send(self, transport: &mut Transport, settings: &RequestSettings) -> Result<HttpResponse> {
+		transport.send(&self.into_request(settings))
+	}
 
-		try!(request.set_write_timeout(Some(Duration::from_secs(30))));
-		try!(request.set_read_timeout(Some(Duration::from_secs(30))));
+	/// Like `send`, but a successful response's body is written to `sink` rather than returned.
+	/// See `Transport::send_to`.
+	pub fn send_to(self, transport: &mut Transport, settings: &RequestSettings, sink: &mut BodySink) -> Result<HttpResponse> {
+		transport.send_to(&self.into_request(settings), sink)
+	}
 
-		if let Some(content_type) = self.content_type {
-			request.headers_mut().set(header::ContentType(content_type))
-		};
+	fn into_request(self, settings: &RequestSettings) -> HttpRequest {
+		let mut headers = self.headers(settings);
 
-		if let Some(access_token) = self.access_token {
-			request.headers_mut().set(header::Authorization(header::Bearer{token: access_token}));
+		let body = if self.multiparts.len() > 0 {
+			let boundary = new_boundary();
+			headers.push(("Content-Type".to_owned(), format!("multipart/form-data; boundary={}", boundary)));
+			encode_multipart(&self.multiparts, &boundary).concat()
+		} else {
+			if let Some(ref content_type) = self.content_type {
+				headers.push(("Content-Type".to_owned(), content_type.to_string()));
+			}
+			self.body.unwrap_or_default()
 		};
 
-		if self.multiparts.len() > 0 {
-			let mut multipart = try!(Multipart::from_request(request));
+		HttpRequest {
+			method: self.method,
+			url: self.url.serialize(),
+			headers: headers,
+			body: body,
+		}
+	}
 
-			for part in self.multiparts {
-				let mut cursor = Cursor::new(part.data);
-				match part.filename {
-					Some(s) => try!(multipart.write_stream(part.name, &mut cursor, Some(&s[..]), part.content_type)),
-					None => try!(multipart.write_stream(part.name, &mut cursor, None, part.content_type)),
-				};
-			}
-			multipart.send()
+	/// Send a multipart request whose `multipart_stream` part is read from `data`, without holding
+	/// it in memory.  `length` is the exact length of `data`, if known; otherwise the request is
+	/// sent chunked.
+	pub fn send_streaming(self, transport: &mut Transport, settings: &RequestSettings, data: &mut Read, length: Option<u64>) -> Result<HttpResponse> {
+		let mut headers = self.headers(settings);
+		let boundary = new_boundary();
+		headers.push(("Content-Type".to_owned(), format!("multipart/form-data; boundary={}", boundary)));
+
+		let mut pieces = encode_multipart(&self.multiparts, &boundary);
+		if pieces.len() != 2 {
+			return Err(Error::BadRequest("send_streaming needs exactly one multipart_stream part".to_owned()));
+		}
+		let after = pieces.pop().unwrap();
+		let before = pieces.pop().unwrap();
+
+		let length = length.map(|length| before.len() as u64 + length + after.len() as u64);
+		let mut body = io::Cursor::new(before).chain(data).chain(io::Cursor::new(after));
+
+		transport.send_streaming(&HttpRequest {
+			method: self.method,
+			url: self.url.serialize(),
+			headers: headers,
+			body: Vec::new(),
+		}, &mut body, length)
+	}
+
+	/// Headers for every request, apart from Content-Type.
+	fn headers(&self, settings: &RequestSettings) -> Vec<(String, String)> {
+		let mut headers = Vec::new();
+
+		if let Some(ref user_agent) = settings.user_agent {
+			headers.push(("User-Agent".to_owned(), user_agent.clone()));
+		}
+
+		if let Some(ref access_token) = self.access_token {
+			headers.push(("Authorization".to_owned(), format!("Bearer {}", access_token)));
+		}
+
+		match self.range {
+			Some((start, Some(end))) => headers.push(("Range".to_owned(), format!("bytes={}-{}", start, end - 1))),
+			Some((start, None)) => headers.push(("Range".to_owned(), format!("bytes={}-", start))),
+			None => (),
+		}
+
+		headers
+	}
+}
+
+
+fn new_boundary() -> String {
+	rand::thread_rng().gen_ascii_chars().take(32).collect()
+}
+
+
+/// Encode `parts` as a multipart/form-data body.  The body is split where the data of a streamed
+/// part goes, so there's one piece without one and two with.
+///
+/// This used to be the `multipart` crate's job, but its client writes straight into a hyper
+/// `Request`, so its bodies can't be handed to a `Transport` (or recorded by one).
+fn encode_multipart(parts: &[RestBuilderMultipartPart], boundary: &str) -> Vec<Vec<u8>> {
+	let mut pieces = vec![Vec::new()];
+
+	for part in parts {
+		let mut body = pieces.pop().unwrap();
+
+		body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"", boundary, quote(&part.name)).as_bytes());
+		if let Some(ref filename) = part.filename {
+			body.extend_from_slice(format!("; filename=\"{}\"", quote(filename)).as_bytes());
+		}
+		body.extend_from_slice(b"\r\n");
+		if let Some(ref content_type) = part.content_type {
+			body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+		}
+		body.extend_from_slice(b"\r\n");
+
+		if let Some(ref data) = part.data {
+			body.extend_from_slice(data);
 		} else {
-			match self.body {
-				Some(ref body) => request.headers_mut().set(header::ContentLength(body.len() as u64)),
-				None => request.headers_mut().set(header::ContentLength(0)),
-			}
-			let mut streaming = try!(request.start());
-			if let Some(body) = self.body {
-				try!(streaming.write_all(&body));
-			}
-			streaming.send()
+			// The streamed data goes between this piece and the next
+			pieces.push(body);
+			body = Vec::new();
 		}
+
+		body.extend_from_slice(b"\r\n");
+		pieces.push(body);
+	}
+
+	pieces.last_mut().unwrap().extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+	pieces
+}
+
+
+/// Make `s` safe to put between the quotes of a Content-Disposition parameter, the way browsers do
+/// it (see "multipart/form-data encoding algorithm" in the HTML standard): `"`, CR and LF are
+/// percent-encoded, and everything else, non-ASCII included, is sent as raw UTF-8.
+fn quote(s: &str) -> String {
+	s.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+
+/// Parse `url` and make sure it's something we can send requests to: http or https, with a path
+/// we can push onto.
+pub fn check_url(url: &str) -> Result<Url> {
+	let parsed = try!(Url::parse(url));
+
+	if (parsed.scheme != "http" && parsed.scheme != "https") || parsed.path().is_none() {
+		return Err(Error::BadUrl(url.to_owned()));
 	}
+
+	Ok(parsed)
 }
 
 
-fn get_host_and_port(url: &Url) -> hyper::error::Result<(String, u16)> {
-	let host = match url.serialize_host() {
-        Some(host) => host,
-        None => return Err(hyper::error::Error::Uri(UrlError::EmptyHost))
-    };
-    let port = match url.port_or_default() {
-        Some(port) => port,
-        None => return Err(hyper::error::Error::Uri(UrlError::InvalidPort))
-    };
-    Ok((host, port))
+
+#[cfg(test)]
+mod test {
+	use super::{RestBuilderMultipartPart, encode_multipart};
+
+	fn part(name: &str, data: Option<&[u8]>, filename: Option<&str>) -> RestBuilderMultipartPart {
+		RestBuilderMultipartPart {
+			name: name.to_owned(),
+			data: data.map(|data| data.to_vec()),
+			filename: filename.map(|filename| filename.to_owned()),
+			content_type: None,
+		}
+	}
+
+	#[test]
+	fn test_encode_multipart() {
+		let parts = [part("metadata", Some(&b"{}"[..]), None), part("content", Some(&b"hello"[..]), Some("hello.txt"))];
+		let pieces = encode_multipart(&parts, "BOUNDARY");
+
+		assert_eq!(pieces.len(), 1);
+		assert_eq!(String::from_utf8(pieces[0].clone()).unwrap(), "--BOUNDARY\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\r\n{}\r\n--BOUNDARY\r\nContent-Disposition: form-data; name=\"content\"; filename=\"hello.txt\"\r\n\r\nhello\r\n--BOUNDARY--\r\n");
+
+		// Split where the streamed data goes
+		let parts = [part("metadata", Some(&b"{}"[..]), None), part("content", None, Some("hello.txt"))];
+		let pieces = encode_multipart(&parts, "BOUNDARY");
+
+		assert_eq!(pieces.len(), 2);
+		assert!(pieces[0].ends_with(b"filename=\"hello.txt\"\r\n\r\n"));
+		assert_eq!(pieces[1], b"\r\n--BOUNDARY--\r\n");
+	}
+
+	#[test]
+	fn test_encode_multipart_quoting() {
+		let parts = [part("content", Some(&b"x"[..]), Some("\"caf\u{e9}\"\r\n\u{65e5}\u{672c}.txt"))];
+		let body = String::from_utf8(encode_multipart(&parts, "BOUNDARY").concat()).unwrap();
+
+		// Quotes and line breaks can't end the parameter or the header; non-ASCII goes through as UTF-8
+		assert!(body.contains("; filename=\"%22caf\u{e9}%22%0D%0A\u{65e5}\u{672c}.txt\"\r\n"));
+		assert_eq!(body.lines().filter(|line| line.starts_with("Content-Disposition")).count(), 1);
+
+		let parts = [part("a\"b", Some(&b"x"[..]), None)];
+		let body = String::from_utf8(encode_multipart(&parts, "BOUNDARY").concat()).unwrap();
+		assert!(body.contains("name=\"a%22b\"\r\n"));
+	}
 }
@@ -0,0 +1,438 @@
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::str;
+use rustc_serialize::{json, Decodable, Encodable};
+use rustc_serialize::hex::{ToHex, FromHex};
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use rand::{OsRng, Rng};
+use fs2::FileExt;
+use error::{Result, Error};
+use super::{Authorization, Endpoint, read_json_file, write_json_file};
+
+
+/// Where `Client` keeps its credentials (`Authorization`) and the account's `Endpoint` between runs.
+/// `load_*` returns None when nothing has been saved yet.
+pub trait TokenStore {
+	fn load_authorization(&mut self) -> Result<Option<Authorization>>;
+	fn save_authorization(&mut self, authorization: &Authorization) -> Result<()>;
+	fn load_endpoint(&mut self) -> Result<Option<Endpoint>>;
+	fn save_endpoint(&mut self, endpoint: &Endpoint) -> Result<()>;
+	/// Forget the saved Authorization and Endpoint.
+	fn clear(&mut self) -> Result<()>;
+
+	/// Take an exclusive lock on the store, blocking until any other holder releases it.
+	/// `Client` holds this while refreshing tokens and saving, so that several processes sharing
+	/// a store don't refresh at the same time and overwrite each other's refresh token.
+	/// Stores that can't be shared between processes don't need to lock anything.
+	fn lock(&mut self) -> Result<StoreLock> {
+		Ok(StoreLock::none())
+	}
+}
+
+
+/// An exclusive lock on a `TokenStore`.  Released when dropped.
+pub struct StoreLock {
+	file: Option<File>,
+}
+
+impl StoreLock {
+	/// A lock that doesn't lock anything.
+	pub fn none() -> StoreLock {
+		StoreLock {
+			file: None,
+		}
+	}
+
+	/// Take an advisory, cross-process lock on a lock file inside `dir`.
+	pub fn lock_dir<P: AsRef<Path>>(dir: P) -> Result<StoreLock> {
+		try!(fs::create_dir_all(&dir));
+		let file = try!(OpenOptions::new().write(true).create(true).open(dir.as_ref().join("lock")));
+		try!(file.lock_exclusive());
+
+		Ok(StoreLock {
+			file: Some(file),
+		})
+	}
+}
+
+impl Drop for StoreLock {
+	fn drop(&mut self) {
+		if let Some(ref file) = self.file {
+			let _ = file.unlock();
+		}
+	}
+}
+
+
+/// The default store: plain JSON files (authorization.json and endpoint.json) in a directory.
+pub struct JsonFileStore {
+	dir: PathBuf,
+}
+
+impl JsonFileStore {
+	pub fn new<P: AsRef<Path>>(dir: P) -> JsonFileStore {
+		JsonFileStore {
+			dir: dir.as_ref().to_path_buf(),
+		}
+	}
+
+	fn load<T: Decodable>(&self, filename: &str) -> Result<Option<T>> {
+		match read_json_file(self.dir.join(filename)) {
+			Ok(value) => Ok(Some(value)),
+			Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+
+	fn save<T: Encodable>(&self, filename: &str, value: &T) -> Result<()> {
+		try!(fs::create_dir_all(&self.dir));
+		write_json_file(self.dir.join(filename), value)
+	}
+}
+
+
+/// Remove a file, treating an already missing file as success.
+pub fn remove_file_if_exists<P: AsRef<Path>>(path: P) -> Result<()> {
+	match fs::remove_file(path) {
+		Ok(_) => Ok(()),
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+		Err(err) => Err(Error::from(err)),
+	}
+}
+
+impl TokenStore for JsonFileStore {
+	fn load_authorization(&mut self) -> Result<Option<Authorization>> {
+		self.load("authorization.json")
+	}
+
+	fn save_authorization(&mut self, authorization: &Authorization) -> Result<()> {
+		self.save("authorization.json", authorization)
+	}
+
+	fn load_endpoint(&mut self) -> Result<Option<Endpoint>> {
+		self.load("endpoint.json")
+	}
+
+	fn save_endpoint(&mut self, endpoint: &Endpoint) -> Result<()> {
+		self.save("endpoint.json", endpoint)
+	}
+
+	fn clear(&mut self) -> Result<()> {
+		try!(remove_file_if_exists(self.dir.join("authorization.json")));
+		remove_file_if_exists(self.dir.join("endpoint.json"))
+	}
+
+	fn lock(&mut self) -> Result<StoreLock> {
+		StoreLock::lock_dir(&self.dir)
+	}
+}
+
+
+/// Keeps everything in memory; nothing survives the process.  Useful for tests and short lived
+/// jobs that are handed their credentials up front.
+#[derive(Default)]
+pub struct MemoryStore {
+	authorization: Option<Authorization>,
+	endpoint: Option<Endpoint>,
+}
+
+impl MemoryStore {
+	pub fn new() -> MemoryStore {
+		MemoryStore::default()
+	}
+
+	/// A store that starts out holding `authorization`.
+	pub fn with_authorization(authorization: Authorization) -> MemoryStore {
+		MemoryStore {
+			authorization: Some(authorization),
+			endpoint: None,
+		}
+	}
+}
+
+impl TokenStore for MemoryStore {
+	fn load_authorization(&mut self) -> Result<Option<Authorization>> {
+		Ok(self.authorization.clone())
+	}
+
+	fn save_authorization(&mut self, authorization: &Authorization) -> Result<()> {
+		self.authorization = Some(authorization.clone());
+		Ok(())
+	}
+
+	fn load_endpoint(&mut self) -> Result<Option<Endpoint>> {
+		Ok(self.endpoint.clone())
+	}
+
+	fn save_endpoint(&mut self, endpoint: &Endpoint) -> Result<()> {
+		self.endpoint = Some(endpoint.clone());
+		Ok(())
+	}
+
+	fn clear(&mut self) -> Result<()> {
+		self.authorization = None;
+		self.endpoint = None;
+		Ok(())
+	}
+}
+
+
+/// Like `JsonFileStore`, but the files are encrypted with a key derived from a passphrase, so
+/// the refresh token isn't sitting on disk in plaintext.
+/// The key is derived with scrypt (fresh random salt on every save) and the JSON is sealed
+/// with ChaCha20-Poly1305.  A wrong passphrase or tampered file results in `Error::BadPassphrase`.
+pub struct EncryptedFileStore {
+	dir: PathBuf,
+	passphrase: String,
+}
+
+/// On-disk format of an `EncryptedFileStore` file.  All binary fields are hex encoded.
+#[derive(RustcEncodable, RustcDecodable)]
+struct EncryptedFile {
+	scrypt_log_n: u8,
+	scrypt_r: u32,
+	scrypt_p: u32,
+	salt: String,
+	nonce: String,
+	tag: String,
+	ciphertext: String,
+}
+
+const SCRYPT_LOG_N: u8 = 14;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Limits on the scrypt parameters we'll accept from a file, so that a corrupt or tampered file
+/// can't make us use gigabytes of memory or spin for hours.  Well above what we write.
+const SCRYPT_MAX_LOG_N: u8 = 20;
+const SCRYPT_MAX_P: u32 = 16;
+const SCRYPT_MAX_MEMORY: u64 = 1024 * 1024 * 1024;
+
+impl EncryptedFileStore {
+	pub fn new<P: AsRef<Path>>(dir: P, passphrase: &str) -> EncryptedFileStore {
+		EncryptedFileStore {
+			dir: dir.as_ref().to_path_buf(),
+			passphrase: passphrase.to_owned(),
+		}
+	}
+
+	fn derive_key(&self, salt: &[u8], log_n: u8, r: u32, p: u32) -> [u8; 32] {
+		let mut key = [0u8; 32];
+		scrypt(self.passphrase.as_bytes(), salt, &ScryptParams::new(log_n, r, p), &mut key);
+		key
+	}
+
+	fn load<T: Decodable>(&self, filename: &str) -> Result<Option<T>> {
+		let file: EncryptedFile = match read_json_file(self.dir.join(filename)) {
+			Ok(file) => file,
+			Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err),
+		};
+
+		let salt = try!(file.salt.from_hex().map_err(|_| Error::BadPassphrase));
+		let nonce = try!(file.nonce.from_hex().map_err(|_| Error::BadPassphrase));
+		let tag = try!(file.tag.from_hex().map_err(|_| Error::BadPassphrase));
+		let ciphertext = try!(file.ciphertext.from_hex().map_err(|_| Error::BadPassphrase));
+
+		if nonce.len() != 8 || tag.len() != 16 || !scrypt_params_acceptable(file.scrypt_log_n, file.scrypt_r, file.scrypt_p) {
+			return Err(Error::BadPassphrase);
+		}
+
+		let key = self.derive_key(&salt, file.scrypt_log_n, file.scrypt_r, file.scrypt_p);
+		let mut plaintext = vec![0u8; ciphertext.len()];
+
+		if !ChaCha20Poly1305::new(&key, &nonce, filename.as_bytes()).decrypt(&ciphertext, &mut plaintext, &tag) {
+			return Err(Error::BadPassphrase);
+		}
+
+		let plaintext = try!(str::from_utf8(&plaintext).map_err(|_| Error::BadPassphrase));
+		Ok(Some(try!(json::decode(plaintext))))
+	}
+
+	fn save<T: Encodable>(&self, filename: &str, value: &T) -> Result<()> {
+		let plaintext = try!(json::encode(value)).into_bytes();
+
+		let mut rng = try!(OsRng::new());
+		let mut salt = [0u8; 32];
+		let mut nonce = [0u8; 8];
+		rng.fill_bytes(&mut salt);
+		rng.fill_bytes(&mut nonce);
+
+		let key = self.derive_key(&salt, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P);
+		let mut ciphertext = vec![0u8; plaintext.len()];
+		let mut tag = [0u8; 16];
+
+		// The filename is authenticated too, so the files can't be swapped for one another.
+		ChaCha20Poly1305::new(&key, &nonce, filename.as_bytes()).encrypt(&plaintext, &mut ciphertext, &mut tag);
+
+		let file = EncryptedFile {
+			scrypt_log_n: SCRYPT_LOG_N,
+			scrypt_r: SCRYPT_R,
+			scrypt_p: SCRYPT_P,
+			salt: salt.to_hex(),
+			nonce: nonce.to_hex(),
+			tag: tag.to_hex(),
+			ciphertext: ciphertext.to_hex(),
+		};
+
+		try!(fs::create_dir_all(&self.dir));
+		write_json_file(self.dir.join(filename), &file)
+	}
+}
+
+/// True if scrypt parameters read from a file are ones `ScryptParams::new` accepts, and within
+/// our limits.
+fn scrypt_params_acceptable(log_n: u8, r: u32, p: u32) -> bool {
+	if log_n == 0 || log_n > SCRYPT_MAX_LOG_N || r == 0 || p == 0 || p > SCRYPT_MAX_P {
+		return false;
+	}
+
+	// scrypt requires N < 2^(128 * r / 8)
+	if (log_n as u64) >= 16 * r as u64 {
+		return false;
+	}
+
+	128 * r as u64 * (1u64 << log_n) <= SCRYPT_MAX_MEMORY
+}
+
+impl TokenStore for EncryptedFileStore {
+	fn load_authorization(&mut self) -> Result<Option<Authorization>> {
+		self.load("authorization.json.enc")
+	}
+
+	fn save_authorization(&mut self, authorization: &Authorization) -> Result<()> {
+		self.save("authorization.json.enc", authorization)
+	}
+
+	fn load_endpoint(&mut self) -> Result<Option<Endpoint>> {
+		self.load("endpoint.json.enc")
+	}
+
+	fn save_endpoint(&mut self, endpoint: &Endpoint) -> Result<()> {
+		self.save("endpoint.json.enc", endpoint)
+	}
+
+	fn clear(&mut self) -> Result<()> {
+		try!(remove_file_if_exists(self.dir.join("authorization.json.enc")));
+		remove_file_if_exists(self.dir.join("endpoint.json.enc"))
+	}
+
+	fn lock(&mut self) -> Result<StoreLock> {
+		StoreLock::lock_dir(&self.dir)
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{TokenStore, EncryptedFileStore, EncryptedFile, MemoryStore, JsonFileStore};
+	use super::super::Authorization;
+	use error::Error;
+	use rustc_serialize::json;
+	use tempdir::TempDir;
+	use std::fs::File;
+	use std::io::{Read, Write};
+	use std::sync::mpsc;
+	use std::thread;
+	use std::time::Duration;
+
+	fn test_authorization() -> Authorization {
+		Authorization {
+			access_token: "Atza|access".to_owned(),
+			refresh_token: "Atzr|refresh".to_owned(),
+			token_type: "bearer".to_owned(),
+			date_last_updated: 1460000000,
+			expires_at: Some(1460003600),
+			scopes: Some(vec!["clouddrive:read_all".to_owned()]),
+		}
+	}
+
+	#[test]
+	fn test_memory_store() {
+		let mut store = MemoryStore::new();
+		assert!(store.load_authorization().unwrap().is_none());
+		store.save_authorization(&test_authorization()).unwrap();
+		assert_eq!(store.load_authorization().unwrap().unwrap().refresh_token, "Atzr|refresh");
+	}
+
+	#[test]
+	fn test_json_file_store_lock() {
+		let dir = TempDir::new("rust-acd-test").unwrap();
+		let lock = JsonFileStore::new(dir.path()).lock().unwrap();
+
+		// A second store on the same directory (as another process would have) must wait for the lock
+		let (tx, rx) = mpsc::channel();
+		let path = dir.path().to_path_buf();
+		let other = thread::spawn(move || {
+			let _lock = JsonFileStore::new(&path).lock().unwrap();
+			tx.send(()).unwrap();
+		});
+
+		thread::sleep(Duration::from_millis(200));
+		assert!(rx.try_recv().is_err());
+
+		drop(lock);
+		rx.recv().unwrap();
+		other.join().unwrap();
+	}
+
+	#[test]
+	fn test_encrypted_file_store() {
+		let dir = TempDir::new("rust-acd-test").unwrap();
+		let mut store = EncryptedFileStore::new(dir.path(), "correct horse battery staple");
+
+		assert!(store.load_authorization().unwrap().is_none());
+		store.save_authorization(&test_authorization()).unwrap();
+
+		// Refresh token must not be readable on disk
+		let mut contents = String::new();
+		File::open(dir.path().join("authorization.json.enc")).unwrap().read_to_string(&mut contents).unwrap();
+		assert!(!contents.contains("Atzr|refresh"));
+
+		let authorization = store.load_authorization().unwrap().unwrap();
+		assert_eq!(authorization.refresh_token, "Atzr|refresh");
+		assert_eq!(authorization.expires_at, Some(1460003600));
+
+		store.clear().unwrap();
+		assert!(store.load_authorization().unwrap().is_none());
+		store.save_authorization(&test_authorization()).unwrap();
+
+		match EncryptedFileStore::new(dir.path(), "wrong").load_authorization() {
+			Err(Error::BadPassphrase) => (),
+			_ => panic!("a wrong passphrase should fail to decrypt"),
+		}
+	}
+
+	#[test]
+	fn test_encrypted_file_store_bad_params() {
+		let dir = TempDir::new("rust-acd-test").unwrap();
+		let mut store = EncryptedFileStore::new(dir.path(), "correct horse battery staple");
+		store.save_authorization(&test_authorization()).unwrap();
+
+		let mut contents = String::new();
+		File::open(dir.path().join("authorization.json.enc")).unwrap().read_to_string(&mut contents).unwrap();
+		let good: EncryptedFile = json::decode(&contents).unwrap();
+
+		// Parameters that would make scrypt panic, or run out of memory, are rejected up front
+		for &(log_n, r, p) in &[(0, 8, 1), (14, 0, 1), (14, 8, 0), (63, 8, 1), (20, 1, 1), (14, 0xffffffff, 1), (14, 8, 0xffffffff)] {
+			let tampered = EncryptedFile {
+				scrypt_log_n: log_n,
+				scrypt_r: r,
+				scrypt_p: p,
+				salt: good.salt.clone(),
+				nonce: good.nonce.clone(),
+				tag: good.tag.clone(),
+				ciphertext: good.ciphertext.clone(),
+			};
+			File::create(dir.path().join("authorization.json.enc")).unwrap().write_all(json::encode(&tampered).unwrap().as_bytes()).unwrap();
+
+			match store.load_authorization() {
+				Err(Error::BadPassphrase) => (),
+				_ => panic!("tampered scrypt parameters should be rejected"),
+			}
+		}
+	}
+}
@@ -0,0 +1,520 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::str;
+use std::path::{Path, PathBuf};
+use hyper::client::request::Request;
+use hyper::client::pool::Pool;
+use hyper::http::{self, Protocol};
+use hyper::method::Method;
+use hyper::header;
+use hyper::error::Error as HyperError;
+use url::{Url, form_urlencoded};
+use url::ParseError as UrlError;
+use rustc_serialize::json;
+use rustc_serialize::base64::{self, ToBase64, FromBase64};
+use std::time::Duration;
+use error::{Result, Error};
+use super::{read_json_file, write_json_file};
+
+
+/// An HTTP request, fully built and ready to send.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+	pub method: Method,
+	pub url: String,
+	/// Headers other than Content-Length, which the transport sets from `body`
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>,
+}
+
+/// An HTTP response, read in full.
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+	pub status: u16,
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		find_header(&self.headers, name)
+	}
+}
+
+impl HttpResponse {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		find_header(&self.headers, name)
+	}
+
+	/// Where the body starts in the whole resource, going by the Content-Range header
+	/// ("bytes 100-199/1000" starts at 100).
+	pub fn content_range_start(&self) -> Option<u64> {
+		let value = match self.header("Content-Range") {
+			Some(value) => value.trim(),
+			None => return None,
+		};
+		if !value.starts_with("bytes ") {
+			return None;
+		}
+		value["bytes ".len()..].split('-').next().and_then(|start| start.trim().parse().ok())
+	}
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+	let name = name.to_lowercase();
+	headers.iter().find(|x| x.0.to_lowercase() == name).map(|x| &x.1[..])
+}
+
+fn is_success(status: u16) -> bool {
+	status >= 200 && status < 300
+}
+
+
+/// Where `Transport::send_to` writes the body of a successful response.
+pub trait BodySink: Write {
+	/// Called with the status and headers of the response (its `body` is empty) before any of the
+	/// body is written, so the sink can work out what it's getting; e.g. a download can tell
+	/// whether the server honoured its Range.  An error stops the body from being written.
+	fn begin(&mut self, response: &HttpResponse) -> io::Result<()>;
+}
+
+
+/// Sends `Client`'s requests.  The default, `HyperTransport`, goes over the network; others can
+/// record, replay or fake the conversation.  See `ClientBuilder::transport`.
+pub trait Transport {
+	fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse>;
+
+	/// Send `request` with its body read from `body` rather than `request.body`.  `length` is the
+	/// exact length of `body`, if known.  Implementations must read `body` to the end on success.
+	///
+	/// The default reads the whole body into memory and calls `send`.
+	fn send_streaming(&mut self, request: &HttpRequest, body: &mut Read, _length: Option<u64>) -> Result<HttpResponse> {
+		let mut request = request.clone();
+		request.body.clear();
+		try!(body.read_to_end(&mut request.body));
+		self.send(&request)
+	}
+
+	/// Send `request`, and if the response is a success (2xx), write its body to `sink` rather
+	/// than returning it, calling `sink.begin` first.  Other responses are returned as usual.
+	/// Implementations should write the body as it arrives, rather than holding it in memory.
+	///
+	/// The default reads the whole response into memory and then writes it out.
+	fn send_to(&mut self, request: &HttpRequest, sink: &mut BodySink) -> Result<HttpResponse> {
+		let mut response = try!(self.send(request));
+
+		if is_success(response.status) {
+			let body = mem::replace(&mut response.body, Vec::new());
+			try!(sink.begin(&response));
+			try!(sink.write_all(&body));
+		}
+
+		Ok(response)
+	}
+}
+
+
+/// Sends requests over the network with hyper, reusing connections.
+pub struct HyperTransport {
+	protocol: Box<Protocol>,
+	read_timeout: Option<Duration>,
+	write_timeout: Option<Duration>,
+}
+
+impl HyperTransport {
+	/// Timeouts apply to each read and write on the socket; None waits forever.
+	pub fn new(read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> HyperTransport {
+		HyperTransport {
+			protocol: Box::new(http::h1::Http11Protocol::with_connector(Pool::new(Default::default()))),
+			read_timeout: read_timeout,
+			write_timeout: write_timeout,
+		}
+	}
+}
+
+impl HyperTransport {
+	/// Send `request` with its body read from `body`, and write the response body to
+	/// `response_sink` if there is one and the response is a success; otherwise it's returned.
+	fn exchange(&mut self, request: &HttpRequest, body: &mut Read, length: Option<u64>, response_sink: Option<&mut BodySink>) -> Result<HttpResponse> {
+		let url = try!(Url::parse(&request.url));
+		let message = {
+			let (host, port) = try!(get_host_and_port(&url));
+			try!(self.protocol.new_message(&host, port, &*url.scheme))
+		};
+
+		let mut hyper_request = try!(Request::with_message(request.method.clone(), url, message));
+
+		try!(hyper_request.set_write_timeout(self.write_timeout));
+		try!(hyper_request.set_read_timeout(self.read_timeout));
+
+		for &(ref name, ref value) in &request.headers {
+			hyper_request.headers_mut().set_raw(name.clone(), vec![value.clone().into_bytes()]);
+		}
+
+		// Without a length, hyper sends the body chunked
+		if let Some(length) = length {
+			hyper_request.headers_mut().set(header::ContentLength(length));
+		}
+
+		let mut streaming = try!(hyper_request.start());
+		let sent = try!(io::copy(body, &mut streaming));
+
+		// Otherwise the server would sit waiting for the rest of a short body until it times out.
+		if let Some(length) = length {
+			if sent != length {
+				return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("Request body was {} bytes, expected {}", sent, length))));
+			}
+		}
+
+		let mut response = try!(streaming.send());
+		let mut http_response = HttpResponse {
+			status: response.status.to_u16(),
+			headers: response.headers.iter().map(|header| (header.name().to_owned(), header.value_string())).collect(),
+			body: Vec::new(),
+		};
+
+		let response_sink = if is_success(http_response.status) { response_sink } else { None };
+		let received = match response_sink {
+			Some(sink) => {
+				try!(sink.begin(&http_response));
+				try!(io::copy(&mut response, sink))
+			},
+			None => try!(response.read_to_end(&mut http_response.body)) as u64,
+		};
+
+		// A connection dropped mid-response can look like a short, but otherwise normal, body.
+		if let Some(&header::ContentLength(length)) = response.headers.get::<header::ContentLength>() {
+			if received != length {
+				return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Response body was {} bytes, expected {}", received, length))));
+			}
+		}
+
+		Ok(http_response)
+	}
+}
+
+impl Transport for HyperTransport {
+	fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse> {
+		self.exchange(request, &mut &request.body[..], Some(request.body.len() as u64), None)
+	}
+
+	fn send_streaming(&mut self, request: &HttpRequest, body: &mut Read, length: Option<u64>) -> Result<HttpResponse> {
+		self.exchange(request, body, length, None)
+	}
+
+	fn send_to(&mut self, request: &HttpRequest, sink: &mut BodySink) -> Result<HttpResponse> {
+		self.exchange(request, &mut &request.body[..], Some(request.body.len() as u64), Some(sink))
+	}
+}
+
+
+fn get_host_and_port(url: &Url) -> ::hyper::error::Result<(String, u16)> {
+	let host = match url.serialize_host() {
+		Some(host) => host,
+		None => return Err(HyperError::Uri(UrlError::EmptyHost))
+	};
+	let port = match url.port_or_default() {
+		Some(port) => port,
+		None => return Err(HyperError::Uri(UrlError::InvalidPort))
+	};
+	Ok((host, port))
+}
+
+
+/// Form fields that hold credentials, which never make it into a fixture file.
+const SECRET_FORM_FIELDS: &'static [&'static str] = &["client_secret", "code", "refresh_token", "token"];
+
+/// Likewise for JSON members in responses.
+const SECRET_JSON_FIELDS: &'static [&'static str] = &["access_token", "refresh_token"];
+
+/// What secrets are replaced with in fixture files.
+const REDACTED: &'static str = "REDACTED";
+
+
+/// A request/response pair, as stored in a fixture file.  Bodies are stored as text when they're
+/// UTF-8 (so fixtures can be read and edited by hand), otherwise as base64.
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+struct Exchange {
+	method: String,
+	url: String,
+	request_headers: Vec<(String, String)>,
+	request_body: Option<String>,
+	request_body_base64: Option<String>,
+	status: u16,
+	response_headers: Vec<(String, String)>,
+	response_body: Option<String>,
+	response_body_base64: Option<String>,
+}
+
+fn encode_body(body: &[u8]) -> (Option<String>, Option<String>) {
+	match String::from_utf8(body.to_vec()) {
+		Ok(text) => (Some(text), None),
+		Err(_) => (None, Some(body.to_base64(base64::STANDARD))),
+	}
+}
+
+fn decode_body(text: &Option<String>, encoded: &Option<String>) -> Result<Vec<u8>> {
+	match (text, encoded) {
+		(&Some(ref text), _) => Ok(text.clone().into_bytes()),
+		(&None, &Some(ref encoded)) => encoded.from_base64().map_err(|err| Error::BadFixture(format!("Bad base64 body: {}", err))),
+		(&None, &None) => Ok(Vec::new()),
+	}
+}
+
+/// Strip credentials out of a recorded body: the values of SECRET_FORM_FIELDS in form encoded
+/// bodies and of SECRET_JSON_FIELDS in JSON objects.
+fn redact_body(body: &[u8], content_type: Option<&str>) -> Vec<u8> {
+	let text = match str::from_utf8(body) {
+		Ok(text) => text,
+		Err(_) => return body.to_vec(),
+	};
+
+	if content_type.map_or(false, |content_type| content_type.starts_with("application/x-www-form-urlencoded")) {
+		let pairs: Vec<(String, String)> = form_urlencoded::parse(body).into_iter().map(|(name, value)| {
+			if SECRET_FORM_FIELDS.contains(&&name[..]) { (name, REDACTED.to_owned()) } else { (name, value) }
+		}).collect();
+		return form_urlencoded::serialize(pairs).into_bytes();
+	}
+
+	let mut json = match json::Json::from_str(text) {
+		Ok(json) => json,
+		Err(_) => return body.to_vec(),
+	};
+	let mut redacted = false;
+
+	if let Some(object) = json.as_object_mut() {
+		for field in SECRET_JSON_FIELDS {
+			if object.contains_key(*field) {
+				object.insert(field.to_string(), json::Json::String(REDACTED.to_owned()));
+				redacted = true;
+			}
+		}
+	}
+
+	// Leave everything else byte for byte as it was
+	if redacted {
+		json.to_string().into_bytes()
+	} else {
+		body.to_vec()
+	}
+}
+
+fn redact_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+	headers.iter().map(|&(ref name, ref value)| {
+		if name.to_lowercase() == "authorization" {
+			(name.clone(), "Bearer ".to_owned() + REDACTED)
+		} else {
+			(name.clone(), value.clone())
+		}
+	}).collect()
+}
+
+
+/// Passes requests through to another transport, and records every request/response pair to a
+/// fixture file that a `ReplayTransport` can serve back later.  Credentials (the Authorization
+/// header, tokens, client_secret) are redacted before anything is written.
+/// The fixture file is rewritten after every exchange, so it's complete even if the process dies.
+pub struct RecordingTransport {
+	inner: Box<Transport>,
+	path: PathBuf,
+	exchanges: Vec<Exchange>,
+}
+
+impl RecordingTransport {
+	pub fn new<P: AsRef<Path>>(inner: Box<Transport>, path: P) -> RecordingTransport {
+		RecordingTransport {
+			inner: inner,
+			path: path.as_ref().to_path_buf(),
+			exchanges: Vec::new(),
+		}
+	}
+}
+
+impl RecordingTransport {
+	fn record(&mut self, request: &HttpRequest, request_body: &[u8], response: &HttpResponse, response_body: &[u8]) -> Result<()> {
+		let (request_body, request_body_base64) = encode_body(&redact_body(request_body, request.header("Content-Type")));
+		let (response_body, response_body_base64) = encode_body(&redact_body(response_body, response.header("Content-Type")));
+
+		self.exchanges.push(Exchange {
+			method: request.method.to_string(),
+			url: request.url.clone(),
+			request_headers: redact_headers(&request.headers),
+			request_body: request_body,
+			request_body_base64: request_body_base64,
+			status: response.status,
+			response_headers: response.headers.clone(),
+			response_body: response_body,
+			response_body_base64: response_body_base64,
+		});
+		write_json_file(&self.path, &self.exchanges)
+	}
+}
+
+impl Transport for RecordingTransport {
+	fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse> {
+		let response = try!(self.inner.send(request));
+		try!(self.record(request, &request.body, &response, &response.body));
+		Ok(response)
+	}
+
+	/// Streamed request bodies (uploads) can be huge, so they're left out of the fixture.
+	fn send_streaming(&mut self, request: &HttpRequest, body: &mut Read, length: Option<u64>) -> Result<HttpResponse> {
+		let response = try!(self.inner.send_streaming(request, body, length));
+		try!(self.record(request, &[], &response, &response.body));
+		Ok(response)
+	}
+
+	/// The body still streams into `sink`; a copy is kept for the fixture.
+	fn send_to(&mut self, request: &HttpRequest, sink: &mut BodySink) -> Result<HttpResponse> {
+		let mut tee = TeeSink {
+			sink: sink,
+			begun: false,
+			copy: Vec::new(),
+		};
+		let response = try!(self.inner.send_to(request, &mut tee));
+
+		if tee.begun {
+			try!(self.record(request, &request.body, &response, &tee.copy));
+		} else {
+			try!(self.record(request, &request.body, &response, &response.body));
+		}
+		Ok(response)
+	}
+}
+
+
+/// Passes everything through to `sink`, keeping a copy of the body.
+struct TeeSink<'a> {
+	sink: &'a mut BodySink,
+	/// Whether `begin` was called, i.e. the body went to the sink rather than the response
+	begun: bool,
+	copy: Vec<u8>,
+}
+
+impl<'a> Write for TeeSink<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = try!(self.sink.write(buf));
+		self.copy.extend_from_slice(&buf[..written]);
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.sink.flush()
+	}
+}
+
+impl<'a> BodySink for TeeSink<'a> {
+	fn begin(&mut self, response: &HttpResponse) -> io::Result<()> {
+		self.begun = true;
+		self.sink.begin(response)
+	}
+}
+
+
+/// Serves responses from a fixture file written by `RecordingTransport`, without touching the
+/// network.  Each request is answered by the first not-yet-used exchange with the same method
+/// and URL; a request with no such exchange fails with `Error::BadFixture`.
+pub struct ReplayTransport {
+	/// Exchanges, and whether each has been used yet
+	exchanges: Vec<(Exchange, bool)>,
+}
+
+impl ReplayTransport {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<ReplayTransport> {
+		let exchanges: Vec<Exchange> = try!(read_json_file(path));
+
+		Ok(ReplayTransport {
+			exchanges: exchanges.into_iter().map(|exchange| (exchange, false)).collect(),
+		})
+	}
+
+	/// How many recorded exchanges haven't been replayed yet.
+	pub fn remaining(&self) -> usize {
+		self.exchanges.iter().filter(|x| !x.1).count()
+	}
+}
+
+impl Transport for ReplayTransport {
+	fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse> {
+		let method = request.method.to_string();
+
+		let entry = try!(self.exchanges.iter_mut()
+			.find(|x| !x.1 && x.0.method == method && x.0.url == request.url)
+			.ok_or(Error::BadFixture(format!("No recorded response for {} {}", method, request.url))));
+		entry.1 = true;
+
+		Ok(HttpResponse {
+			status: entry.0.status,
+			headers: entry.0.response_headers.clone(),
+			body: try!(decode_body(&entry.0.response_body, &entry.0.response_body_base64)),
+		})
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{HyperTransport, RecordingTransport, ReplayTransport, Transport, HttpRequest};
+	use error::Error;
+	use hyper::method::Method;
+	use mock::{MockServer, MOCK_CLIENT_SECRET};
+	use std::fs::File;
+	use std::io::Read;
+	use tempdir::TempDir;
+
+	#[test]
+	fn test_record_and_replay() {
+		let temp_dir = TempDir::new("rust-acd-test").unwrap();
+		let fixture = temp_dir.path().join("fixture.json");
+		let server = MockServer::start();
+
+		let file = {
+			let recorder = RecordingTransport::new(Box::new(HyperTransport::new(None, None)), &fixture);
+			let mut client = server.client_builder().transport(Box::new(recorder)).maximum_retry(1).build().unwrap();
+			let folder = client.mkdir(None, "folder").unwrap();
+			let file = client.upload(Some(&folder), "file", b"some data", None).unwrap();
+			assert_eq!(client.download(&file).unwrap(), b"some data");
+			file
+		};
+
+		// Credentials never make it into the fixture
+		let mut recorded = String::new();
+		File::open(&fixture).unwrap().read_to_string(&mut recorded).unwrap();
+		assert!(!recorded.contains(MOCK_CLIENT_SECRET));
+		assert!(!recorded.contains("mock_access_token"));
+		assert!(!recorded.contains("mock_refresh_token"));
+
+		// The same session plays back, without the server
+		let replay = ReplayTransport::open(&fixture).unwrap();
+		assert!(replay.remaining() > 0);
+		let mut client = server.client_builder().transport(Box::new(replay)).maximum_retry(1).build().unwrap();
+		let folder = client.mkdir(None, "folder").unwrap();
+		assert_eq!(client.upload(Some(&folder), "file", b"some data", None).unwrap(), file);
+		assert_eq!(client.download(&file).unwrap(), b"some data");
+		assert_eq!(server.request_log().iter().filter(|x| x.starts_with("GET /cdproxy")).count(), 1);
+
+		// Anything that wasn't recorded fails
+		match client.ls(&folder) {
+			Err(Error::BadFixture(_)) => (),
+			_ => panic!("requests missing from the fixture should fail with BadFixture"),
+		}
+	}
+
+	#[test]
+	fn test_short_streaming_body() {
+		let server = MockServer::start();
+		let mut transport = HyperTransport::new(None, None);
+		let request = HttpRequest {
+			method: Method::Post,
+			url: server.url("/cdproxy/nodes"),
+			headers: Vec::new(),
+			body: Vec::new(),
+		};
+
+		// The body falls short of the length promised; that's an error rather than a hung request
+		match transport.send_streaming(&request, &mut &b"short"[..], Some(10)) {
+			Err(Error::Io(_)) => (),
+			_ => panic!("a body shorter than its length should fail"),
+		}
+	}
+}